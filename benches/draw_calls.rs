@@ -0,0 +1,101 @@
+//! Outlines a grid of cubes, each with a slightly different color, and reports
+//! the number of outline draw calls and the frame time.
+//!
+//! The outlines share a single mesh, so they are batched into one draw call.
+//! Pass the number of cubes as argument, e.g. `cargo bench --bench draw_calls -- 10000`.
+
+use std::time::{Duration, Instant};
+
+use bevy::{app::AppExit, prelude::*, window::PresentMode};
+use bevy_outline::{Outline, OutlineDrawCalls, OutlinePlugin};
+
+/// Frames rendered before measuring, while pipelines are compiled.
+const WARMUP_FRAMES: u32 = 60;
+const MEASURED_FRAMES: u32 = 300;
+
+#[derive(Resource)]
+struct Bench {
+    count: usize,
+    frame: u32,
+    start: Option<Instant>,
+}
+
+fn main() {
+    let count = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1000);
+
+    App::new()
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                title: "bevy_outline draw calls".into(),
+                present_mode: PresentMode::AutoNoVsync,
+                ..default()
+            }),
+            ..default()
+        }))
+        .add_plugins(OutlinePlugin::default())
+        .insert_resource(Bench {
+            count,
+            frame: 0,
+            start: None,
+        })
+        .add_systems(Startup, setup)
+        .add_systems(Update, measure)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    bench: Res<Bench>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let mesh = meshes.add(Cuboid::new(0.5, 0.5, 0.5));
+    let material = materials.add(Color::linear_rgb(0.8, 0.8, 0.8));
+    let side = (bench.count as f32).sqrt().ceil() as usize;
+
+    for i in 0..bench.count {
+        let (x, z) = ((i % side) as f32, (i / side) as f32);
+        commands.spawn((
+            Mesh3d(mesh.clone()),
+            MeshMaterial3d(material.clone()),
+            Transform::from_xyz(x - side as f32 / 2.0, 0.0, z - side as f32 / 2.0),
+            Outline {
+                width: 2.,
+                color: Color::hsl(i as f32 / bench.count as f32 * 360.0, 0.8, 0.5).into(),
+                ..default()
+            },
+        ));
+    }
+
+    commands.spawn(DirectionalLight::default());
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(0.0, side as f32, side as f32).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+}
+
+fn measure(
+    mut bench: ResMut<Bench>,
+    draw_calls: Res<OutlineDrawCalls>,
+    mut exit: EventWriter<AppExit>,
+) {
+    bench.frame += 1;
+    if bench.frame == WARMUP_FRAMES {
+        bench.start = Some(Instant::now());
+    }
+    if bench.frame < WARMUP_FRAMES + MEASURED_FRAMES {
+        return;
+    }
+
+    let elapsed = bench.start.map_or(Duration::ZERO, |start| start.elapsed());
+    println!(
+        "{} outlined cubes: {} outline draw calls per frame, {:.2} ms per frame",
+        bench.count,
+        draw_calls.get(),
+        elapsed.as_secs_f64() * 1000.0 / MEASURED_FRAMES as f64,
+    );
+    exit.send(AppExit::Success);
+}
@@ -1,15 +1,20 @@
 use bevy::{prelude::*, scene::SceneInstanceReady};
 use bevy_outline::{
-    picking::{HoverOutline, OutlinePickingPlugin, PressedOutline, SelectedOutline},
+    picking::{
+        BoxSelection, HoverOutline, OutlineBoxSelectionPlugin, OutlinePickingPlugin,
+        OutlinePickingRoot, PickingOutlines, PressedOutline, SelectedOutline,
+    },
     OutlineMaterial, OutlinePlugin, OutlineRendered,
 };
 
 fn main() {
     let mut app = App::new();
     app.add_plugins(DefaultPlugins)
-        .add_plugins(OutlinePlugin)
+        .add_plugins(OutlinePlugin::default())
         .add_plugins(OutlinePickingPlugin)
+        .add_plugins(OutlineBoxSelectionPlugin::default())
         .add_systems(Startup, (set_picking_outlines, setup))
+        .add_systems(Update, draw_box_selection)
         .run();
 }
 
@@ -17,14 +22,17 @@ fn set_picking_outlines(mut commands: Commands, mut outlines: ResMut<Assets<Outl
     commands.insert_resource(HoverOutline(outlines.add(OutlineMaterial {
         width: 5.,
         color: Color::linear_rgb(1.0, 1.0, 1.0).into(),
+        ..default()
     })));
     commands.insert_resource(SelectedOutline(outlines.add(OutlineMaterial {
         width: 5.,
         color: Color::linear_rgb(1.0, 1.0, 0.2).into(),
+        ..default()
     })));
     commands.insert_resource(PressedOutline(outlines.add(OutlineMaterial {
         width: 5.,
         color: Color::linear_rgb(1.0, 1.0, 0.5).into(),
+        ..default()
     })));
 }
 
@@ -34,6 +42,7 @@ fn setup(
     mut ambient_light: ResMut<AmbientLight>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    mut outlines: ResMut<Assets<OutlineMaterial>>,
 ) {
     // Cube
     commands.spawn((
@@ -49,6 +58,15 @@ fn setup(
         MeshMaterial3d(materials.add(Color::linear_rgb(0.3, 0.2, 0.1))),
         Transform::from_xyz(-2.0, 0.5, 0.0),
         OutlineRendered,
+        // Glows red when hovered, instead of the `HoverOutline`.
+        PickingOutlines {
+            hover: Some(outlines.add(OutlineMaterial {
+                width: 5.,
+                color: Color::linear_rgb(1.0, 0.1, 0.1).into(),
+                ..default()
+            })),
+            ..default()
+        },
     ));
 
     // Torus
@@ -61,8 +79,10 @@ fn setup(
 
     // Monkey head
     commands
-        .spawn(SceneRoot(
-            asset_server.load(GltfAssetLabel::Scene(0).from_asset("head.glb")),
+        .spawn((
+            SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset("head.glb"))),
+            // Outline all the meshes of the scene when any of them is picked.
+            OutlinePickingRoot,
         ))
         .observe(
             |trigger: Trigger<SceneInstanceReady>,
@@ -72,8 +92,7 @@ fn setup(
              mut commands: Commands| {
                 if let Some(entity) = q_children
                     .iter_descendants(trigger.entity())
-                    .filter(|e| q_mesh.get(*e).is_ok())
-                    .next()
+                    .find(|e| q_mesh.get(*e).is_ok())
                 {
                     commands.entity(entity).insert((
                         MeshMaterial3d(materials.add(Color::linear_rgb(0.7, 0.2, 0.5))),
@@ -96,6 +115,19 @@ fn setup(
         Transform::from_xyz(4.0, 8.0, 4.0),
     ));
 
+    // Box selection rectangle
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            border: UiRect::all(Val::Px(1.0)),
+            display: Display::None,
+            ..default()
+        },
+        BorderColor(Color::WHITE),
+        BackgroundColor(Color::srgba(1.0, 1.0, 1.0, 0.1)),
+        BoxSelectionRect,
+    ));
+
     // camera
     let camera_translation = Vec3::new(0.0, 6.0, 12.0);
     commands.spawn((
@@ -103,3 +135,25 @@ fn setup(
         Transform::from_translation(camera_translation).looking_at(Vec3::ZERO, Vec3::Y),
     ));
 }
+
+#[derive(Component)]
+struct BoxSelectionRect;
+
+fn draw_box_selection(
+    box_selection: Res<BoxSelection>,
+    mut q_rect: Query<&mut Node, With<BoxSelectionRect>>,
+) {
+    let Ok(mut node) = q_rect.get_single_mut() else {
+        return;
+    };
+    match box_selection.rect() {
+        Some(rect) => {
+            node.display = Display::Flex;
+            node.left = Val::Px(rect.min.x);
+            node.top = Val::Px(rect.min.y);
+            node.width = Val::Px(rect.width());
+            node.height = Val::Px(rect.height());
+        }
+        None => node.display = Display::None,
+    }
+}
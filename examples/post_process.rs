@@ -0,0 +1,79 @@
+use bevy::{core_pipeline::core_3d::Camera3d, prelude::*};
+use bevy_outline::{OutlinePlugin, OutlinePostProcess};
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .add_plugins(OutlinePlugin::default())
+        .add_systems(Startup, setup)
+        .add_systems(Update, rotate)
+        .run();
+}
+
+#[derive(Component)]
+struct Rotating;
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let white = materials.add(Color::linear_rgb(0.9, 0.9, 0.9));
+
+    // Floor
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(10.0, 10.0))),
+        MeshMaterial3d(white.clone()),
+    ));
+
+    // Walls meeting at creases
+    commands.spawn((
+        Mesh3d(meshes.add(Cuboid::new(4.0, 2.0, 0.2))),
+        MeshMaterial3d(white.clone()),
+        Transform::from_xyz(0.0, 1.0, -2.0),
+    ));
+    commands.spawn((
+        Mesh3d(meshes.add(Cuboid::new(0.2, 2.0, 4.0))),
+        MeshMaterial3d(white.clone()),
+        Transform::from_xyz(-2.0, 1.0, 0.0),
+    ));
+
+    // Intersecting meshes
+    commands.spawn((
+        Mesh3d(meshes.add(Cuboid::default())),
+        MeshMaterial3d(white.clone()),
+        Transform::from_xyz(0.5, 0.5, 0.5),
+        Rotating,
+    ));
+    commands.spawn((
+        Mesh3d(meshes.add(Sphere::new(0.6))),
+        MeshMaterial3d(white),
+        Transform::from_xyz(0.5, 1.0, 0.5),
+    ));
+
+    // Light
+    commands.spawn((
+        PointLight {
+            shadows_enabled: true,
+            intensity: 2_000_000.0,
+            ..default()
+        },
+        Transform::from_xyz(4.0, 8.0, 4.0),
+    ));
+
+    // Camera, drawing creases and intersections as well as silhouettes
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(5.0, 4.0, 6.0).looking_at(Vec3::new(0.0, 0.5, 0.0), Vec3::Y),
+        OutlinePostProcess {
+            width: 2.0,
+            ..default()
+        },
+    ));
+}
+
+fn rotate(time: Res<Time>, mut q_transform: Query<&mut Transform, With<Rotating>>) {
+    for mut t in q_transform.iter_mut() {
+        t.rotate(Quat::from_rotation_y(time.delta_secs() * 0.5));
+    }
+}
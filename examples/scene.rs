@@ -2,11 +2,13 @@ use bevy::{
     core_pipeline::core_3d::Camera3d,
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
-    scene::SceneInstanceReady,
     window::PrimaryWindow,
 };
 // use bevy_obj::ObjPlugin;
-use bevy_outline::{OutlineMaterial, OutlinePlugin, OutlineRendered};
+use bevy_outline::{
+    InheritedOutline, Outline, OutlineHierarchy, OutlineMaterial, OutlineMethod, OutlinePlugin,
+    OutlineRendered, OutlineWidthMode,
+};
 
 fn main() {
     println!(
@@ -17,8 +19,8 @@ fn main() {
     );
     App::new()
         .add_plugins(DefaultPlugins)
-        .add_plugins(OutlinePlugin)
-        .add_systems(Update, (pan_orbit_camera, rotate))
+        .add_plugins(OutlinePlugin::default())
+        .add_systems(Update, (pan_orbit_camera, rotate, pulse))
         .add_systems(Startup, setup)
         .run();
 }
@@ -34,11 +36,15 @@ fn setup(
     let outline_black = outlines.add(OutlineMaterial {
         width: 5.,
         color: Color::linear_rgb(0.0, 0.0, 0.0).into(),
+        ..default()
     });
 
-    let outline_white = outlines.add(OutlineMaterial {
-        width: 3.,
-        color: Color::linear_rgb(1.0, 1.0, 1.0).into(),
+    // Width in world units, so the outline gets thinner as the torus moves away.
+    let outline_world = outlines.add(OutlineMaterial {
+        width: 0.05,
+        color: Color::linear_rgb(0.9, 0.9, 0.2).into(),
+        width_mode: OutlineWidthMode::World,
+        ..default()
     });
 
     // Cube
@@ -55,8 +61,12 @@ fn setup(
         Mesh3d(meshes.add(Mesh::from(Sphere::default()))),
         MeshMaterial3d(materials.add(Color::linear_rgb(0.3, 0.2, 0.1))),
         Transform::from_xyz(-2.0, 0.5, 0.0),
-        OutlineRendered,
-        MeshMaterial3d(outline_white.clone()),
+        // A component instead of a material, animated by `pulse`.
+        Outline {
+            width: 3.,
+            color: Color::linear_rgb(1.0, 1.0, 1.0).into(),
+            ..default()
+        },
     ));
 
     // Torus
@@ -65,38 +75,34 @@ fn setup(
         MeshMaterial3d(materials.add(Color::linear_rgb(0.2, 0.2, 0.5))),
         Transform::from_xyz(6.0, 0.5, 0.0),
         OutlineRendered,
-        MeshMaterial3d(outline_white.clone()),
+        MeshMaterial3d(outline_world),
     ));
 
-    // Monkey head
-    commands
-        .spawn(SceneRoot(
-            asset_server.load(GltfAssetLabel::Scene(0).from_asset("head.glb")),
-        ))
-        .observe(
-            |trigger: Trigger<SceneInstanceReady>,
-             q_children: Query<&Children>,
-             q_mesh: Query<Entity, With<Mesh3d>>,
-             mut materials: ResMut<Assets<StandardMaterial>>,
-             mut outlines: ResMut<Assets<OutlineMaterial>>,
-             mut commands: Commands| {
-                if let Some(entity) = q_children
-                    .iter_descendants(trigger.entity())
-                    .filter(|e| q_mesh.get(*e).is_ok())
-                    .next()
-                {
-                    commands.entity(entity).insert((
-                        MeshMaterial3d(materials.add(Color::linear_rgb(0.7, 0.2, 0.5))),
-                        Transform::from_xyz(-6.0, 0.5, 0.0),
-                        OutlineRendered,
-                        MeshMaterial3d(outlines.add(OutlineMaterial {
-                            width: 5.,
-                            color: Color::linear_rgb(0.7, 0.0, 0.9).into(),
-                        })),
-                    ));
-                }
-            },
-        );
+    // Torus lying on its side, with the inner hull hidden by the stencil
+    commands.spawn((
+        Mesh3d(meshes.add(Mesh::from(Torus::default()))),
+        MeshMaterial3d(materials.add(Color::linear_rgb(0.2, 0.5, 0.2))),
+        Transform::from_xyz(0.0, 0.5, -4.0).with_rotation(Quat::from_rotation_x(0.8)),
+        OutlineRendered,
+        MeshMaterial3d(outlines.add(OutlineMaterial {
+            width: 5.,
+            color: Color::linear_rgb(0.0, 0.0, 0.0).into(),
+            method: Some(OutlineMethod::StencilExtrusion),
+            ..default()
+        })),
+    ));
+
+    // Monkey head, outlining the meshes of the scene once they are spawned
+    commands.spawn((
+        SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset("head.glb"))),
+        Transform::from_xyz(-6.0, 0.5, 0.0),
+        OutlineHierarchy(Outline {
+            width: 5.,
+            color: Color::linear_rgb(0.7, 0.0, 0.9).into(),
+            method: Some(OutlineMethod::JumpFlood),
+            ..default()
+        }),
+    ));
 
     // Light
     ambient_light.brightness = 100.0;
@@ -205,7 +211,7 @@ fn pan_orbit_camera(
             let yaw = Quat::from_rotation_y(-delta_x);
             let pitch = Quat::from_rotation_x(-delta_y);
             transform.rotation = yaw * transform.rotation; // rotate around global y axis
-            transform.rotation = transform.rotation * pitch; // rotate around local x axis
+            transform.rotation *= pitch; // rotate around local x axis
         } else if pan.length_squared() > 0.0 {
             any = true;
             // make panning distance independent of resolution and FOV,
@@ -242,3 +248,9 @@ fn rotate(time: Res<Time>, mut q_transform: Query<&mut Transform, With<OutlineRe
         t.rotate(Quat::from_rotation_x(time.delta_secs()));
     }
 }
+
+fn pulse(time: Res<Time>, mut q_outline: Query<&mut Outline, Without<InheritedOutline>>) {
+    for mut outline in q_outline.iter_mut() {
+        outline.width = 3.0 + 2.0 * time.elapsed_secs().sin();
+    }
+}
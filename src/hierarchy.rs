@@ -0,0 +1,239 @@
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::{Outline, OutlineRendered};
+
+/// Applies an [`Outline`] to every [`Mesh3d`] among the descendants of this
+/// entity, e.g. a [`SceneRoot`], including meshes spawned later.
+///
+/// The outline is removed from the meshes when this component is removed or
+/// when they leave the hierarchy. Meshes with an [`Outline`] of their own are
+/// left untouched, and the closest [`OutlineHierarchy`] wins when they are nested.
+#[derive(Component, Debug, Clone, Default)]
+pub struct OutlineHierarchy(pub Outline);
+
+/// Marks an [`Outline`] applied by the [`OutlineHierarchy`] of an ancestor.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct InheritedOutline {
+    /// The entity with the [`OutlineHierarchy`].
+    pub root: Entity,
+    /// Whether the [`OutlineRendered`] marker was added along with the outline,
+    /// and must be removed with it.
+    added_rendered: bool,
+}
+
+type MeshOutlineQueryData = (
+    Option<&'static Outline>,
+    Option<&'static InheritedOutline>,
+    Has<OutlineRendered>,
+);
+
+type HierarchyChanged = Or<(Changed<OutlineHierarchy>, Changed<Parent>, Added<Mesh3d>)>;
+
+#[allow(clippy::too_many_arguments)]
+pub fn propagate_outline_hierarchy(
+    mut commands: Commands,
+    q_root: Query<&OutlineHierarchy>,
+    q_children: Query<&Children>,
+    q_parent: Query<&Parent>,
+    q_mesh: Query<MeshOutlineQueryData, With<Mesh3d>>,
+    q_inherited: Query<(Entity, &InheritedOutline)>,
+    q_changed: Query<Entity, HierarchyChanged>,
+    mut removed_roots: RemovedComponents<OutlineHierarchy>,
+    mut removed_parents: RemovedComponents<Parent>,
+) {
+    // Only the subtrees of the entities that moved or changed need to be walked,
+    // along with the meshes whose root is gone.
+    let mut dirty: Vec<Entity> = q_changed.iter().chain(removed_parents.read()).collect();
+    if removed_roots.read().count() > 0 {
+        dirty.extend(
+            q_inherited
+                .iter()
+                .filter(|(_, marker)| !q_root.contains(marker.root))
+                .map(|(entity, _)| entity),
+        );
+    }
+
+    let mut visited = HashSet::new();
+    for start in dirty {
+        if visited.contains(&start) {
+            continue;
+        }
+        let closest = std::iter::once(start)
+            .chain(q_parent.iter_ancestors(start))
+            .find(|&ancestor| q_root.contains(ancestor));
+        let mut stack = vec![(start, closest)];
+        while let Some((entity, closest)) = stack.pop() {
+            if !visited.insert(entity) {
+                continue;
+            }
+            let closest = if q_root.contains(entity) {
+                Some(entity)
+            } else {
+                closest
+            };
+            if let Ok(children) = q_children.get(entity) {
+                stack.extend(children.iter().map(|&child| (child, closest)));
+            }
+
+            let Ok((outline, marker, rendered)) = q_mesh.get(entity) else {
+                continue;
+            };
+            let Some((root, hierarchy)) =
+                closest.and_then(|root| Some((root, q_root.get(root).ok()?)))
+            else {
+                // The mesh left the hierarchies.
+                if let Some(marker) = marker {
+                    let mut entity = commands.entity(entity);
+                    entity.remove::<(Outline, InheritedOutline)>();
+                    if marker.added_rendered {
+                        entity.remove::<OutlineRendered>();
+                    }
+                }
+                continue;
+            };
+            match (outline, marker) {
+                // The mesh has an outline of its own.
+                (Some(_), None) => {}
+                (Some(outline), Some(marker)) if marker.root == root && *outline == hierarchy.0 => {
+                }
+                _ => {
+                    let added_rendered = marker.map_or(!rendered, |marker| marker.added_rendered);
+                    commands.entity(entity).insert((
+                        hierarchy.0.clone(),
+                        InheritedOutline {
+                            root,
+                            added_rendered,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_systems(PostUpdate, propagate_outline_hierarchy);
+        app
+    }
+
+    fn outline() -> Outline {
+        Outline {
+            width: 4.0,
+            ..default()
+        }
+    }
+
+    fn inherits(app: &App, entity: Entity) -> bool {
+        let entity = app.world().entity(entity);
+        entity.contains::<Outline>()
+            && entity.contains::<InheritedOutline>()
+            && entity.contains::<OutlineRendered>()
+    }
+
+    #[test]
+    fn applies_the_outline_to_the_meshes_below() {
+        let mut app = app();
+        let root = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        let mesh = app
+            .world_mut()
+            .spawn(Mesh3d::default())
+            .set_parent(root)
+            .id();
+        let without_mesh = app.world_mut().spawn_empty().set_parent(root).id();
+        app.update();
+
+        assert!(inherits(&app, mesh));
+        assert!(!app.world().entity(without_mesh).contains::<Outline>());
+    }
+
+    #[test]
+    fn applies_the_outline_to_meshes_spawned_later() {
+        let mut app = app();
+        let root = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        let child = app.world_mut().spawn_empty().set_parent(root).id();
+        app.update();
+
+        let mesh = app
+            .world_mut()
+            .spawn(Mesh3d::default())
+            .set_parent(child)
+            .id();
+        app.update();
+
+        assert!(inherits(&app, mesh));
+    }
+
+    #[test]
+    fn removes_only_what_it_added() {
+        let mut app = app();
+        let root = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        let mesh = app
+            .world_mut()
+            .spawn(Mesh3d::default())
+            .set_parent(root)
+            .id();
+        let rendered = app
+            .world_mut()
+            .spawn((Mesh3d::default(), OutlineRendered))
+            .set_parent(root)
+            .id();
+        app.update();
+        assert!(inherits(&app, mesh) && inherits(&app, rendered));
+
+        app.world_mut()
+            .entity_mut(root)
+            .remove::<OutlineHierarchy>();
+        app.update();
+
+        let mesh = app.world().entity(mesh);
+        assert!(!mesh.contains::<Outline>());
+        assert!(!mesh.contains::<InheritedOutline>());
+        assert!(!mesh.contains::<OutlineRendered>());
+        let rendered = app.world().entity(rendered);
+        assert!(!rendered.contains::<Outline>());
+        assert!(rendered.contains::<OutlineRendered>());
+    }
+
+    #[test]
+    fn removes_the_outline_of_meshes_leaving_the_hierarchy() {
+        let mut app = app();
+        let root = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        let other = app.world_mut().spawn(Transform::default()).id();
+        let mesh = app
+            .world_mut()
+            .spawn(Mesh3d::default())
+            .set_parent(root)
+            .id();
+        app.update();
+        assert!(inherits(&app, mesh));
+
+        app.world_mut().entity_mut(mesh).set_parent(other);
+        app.update();
+        assert!(!app.world().entity(mesh).contains::<Outline>());
+    }
+
+    #[test]
+    fn leaves_unchanged_hierarchies_alone() {
+        let mut app = app();
+        let root = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        let mesh = app
+            .world_mut()
+            .spawn(Mesh3d::default())
+            .set_parent(root)
+            .id();
+        let other = app.world_mut().spawn(OutlineHierarchy(outline())).id();
+        app.update();
+
+        // The game animates the inherited outline.
+        app.world_mut().get_mut::<Outline>(mesh).unwrap().width = 8.0;
+        app.world_mut().spawn(Mesh3d::default()).set_parent(other);
+        app.update();
+        assert_eq!(app.world().get::<Outline>(mesh).unwrap().width, 8.0);
+    }
+}
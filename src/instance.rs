@@ -0,0 +1,346 @@
+//! Per-instance outline data, so outlines of the same mesh batch into one draw.
+//!
+//! Instead of a bind group per [`OutlineMaterial`](crate::OutlineMaterial), the
+//! transform, width and colors of every outlined entity are written to a single
+//! instance buffer while the outline phases are batched, and the shader reads
+//! them back by instance index. Hundreds of units with slightly different
+//! colors are then drawn with as many draw calls as there are distinct meshes.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use bevy::{
+    ecs::{
+        query::ROQueryItem,
+        system::{lifetimeless::SRes, SystemParamItem},
+    },
+    math::Affine3A,
+    pbr::{
+        RenderMaterialInstances, RenderMeshInstanceFlags, RenderMeshInstances, SetMeshBindGroup,
+    },
+    prelude::*,
+    render::{
+        batching::{
+            gpu_preprocessing::IndirectParametersBuffer,
+            no_gpu_preprocessing::BatchedInstanceBuffer, GetBatchData, GetFullBatchData,
+        },
+        mesh::allocator::MeshAllocator,
+        render_asset::RenderAssets,
+        render_phase::{
+            DrawFunctionId, PhaseItem, PhaseItemExtraIndex, RenderCommand, RenderCommandResult,
+            TrackedRenderPass,
+        },
+        render_resource::{BindGroup, BindGroupEntry, ShaderType},
+        renderer::RenderDevice,
+        sync_world::{MainEntity, MainEntityHashMap},
+        Extract,
+    },
+};
+use nonmax::NonMaxU32;
+
+use crate::{
+    outline::RenderOutlineInstances, OutlineMaterial, OutlinePipeline, OutlineRendered,
+    PreparedOutlineMaterial,
+};
+
+/// The data of an outlined entity, indexed by instance index in the shader.
+#[derive(Clone, ShaderType)]
+pub(crate) struct OutlineInstanceUniform {
+    world_from_local: Mat4,
+    /// The inverse transpose of `world_from_local`, to transform normals.
+    normal_from_local: Mat3,
+    color: Vec4,
+    occluded_color: Vec4,
+    /// The near and far distances of the [`OutlineFalloff`](crate::OutlineFalloff), followed by the
+    /// scale of the width and the alpha at the far distance.
+    falloff: Vec4,
+    /// The minimum and maximum widths of the falloff.
+    width_range: Vec2,
+    width: f32,
+    /// The index of the first vertex of the mesh in the vertex buffer it shares
+    /// with other meshes.
+    first_vertex_index: u32,
+}
+
+impl OutlineInstanceUniform {
+    fn new(
+        outline: &PreparedOutlineMaterial,
+        world_from_local: &Affine3A,
+        first_vertex_index: u32,
+    ) -> Self {
+        Self {
+            world_from_local: Mat4::from(*world_from_local),
+            normal_from_local: Mat3::from(world_from_local.matrix3).inverse().transpose(),
+            color: outline.color.to_vec4(),
+            occluded_color: outline.occluded_color.to_vec4(),
+            falloff: Vec4::new(
+                outline.falloff.near,
+                outline.falloff.far,
+                outline.falloff.far_width_scale,
+                outline.falloff.far_alpha,
+            ),
+            width_range: Vec2::new(outline.falloff.min_width, outline.falloff.max_width),
+            width: outline.width,
+            first_vertex_index,
+        }
+    }
+}
+
+/// The transforms of the outlined entities.
+///
+/// The mesh instances only keep them when mesh uniforms are built on the CPU.
+#[derive(Resource, Default)]
+pub(crate) struct RenderOutlineTransforms(MainEntityHashMap<Affine3A>);
+
+pub(crate) fn extract_outline_transforms(
+    mut transforms: ResMut<RenderOutlineTransforms>,
+    q_transform: Extract<Query<(Entity, &GlobalTransform), With<OutlineRendered>>>,
+) {
+    transforms.0.clear();
+    transforms.0.extend(
+        q_transform
+            .iter()
+            .map(|(entity, transform)| (entity.into(), transform.affine())),
+    );
+}
+
+/// Batches the outline phases, writing an [`OutlineInstanceUniform`] per drawn
+/// entity to the [`BatchedInstanceBuffer`] of the outlines.
+///
+/// The instance buffer is always built on the CPU, even when the mesh uniforms
+/// are built on the GPU.
+pub(crate) struct OutlineBatch;
+
+impl GetBatchData for OutlineBatch {
+    type Param = (
+        SRes<RenderOutlineInstances>,
+        SRes<RenderMaterialInstances<OutlineMaterial>>,
+        SRes<RenderAssets<PreparedOutlineMaterial>>,
+        SRes<RenderOutlineTransforms>,
+        SRes<RenderMeshInstances>,
+        SRes<MeshAllocator>,
+    );
+    type CompareData = AssetId<Mesh>;
+    type BufferData = OutlineInstanceUniform;
+
+    fn get_batch_data(
+        (outlines, material_instances, materials, transforms, mesh_instances, mesh_allocator): &SystemParamItem<Self::Param>,
+        (_entity, main_entity): (Entity, MainEntity),
+    ) -> Option<(Self::BufferData, Option<Self::CompareData>)> {
+        let outline = outlines.get(&main_entity, material_instances, materials)?;
+        let world_from_local = transforms.0.get(&main_entity)?;
+        let mesh_instance = mesh_instances.render_mesh_queue_data(main_entity)?;
+        let first_vertex_index = mesh_allocator
+            .mesh_vertex_slice(&mesh_instance.mesh_asset_id)
+            .map_or(0, |slice| slice.range.start);
+        // Skinned and morphed meshes opt out of automatic batching, as their
+        // joints and weights are bound per entity.
+        let batchable = mesh_instance
+            .flags
+            .contains(RenderMeshInstanceFlags::AUTOMATIC_BATCHING);
+        Some((
+            OutlineInstanceUniform::new(outline, world_from_local, first_vertex_index),
+            batchable.then_some(mesh_instance.mesh_asset_id),
+        ))
+    }
+}
+
+impl GetFullBatchData for OutlineBatch {
+    type BufferInputData = ();
+
+    fn get_binned_batch_data(
+        param: &SystemParamItem<Self::Param>,
+        query_item: (Entity, MainEntity),
+    ) -> Option<Self::BufferData> {
+        Self::get_batch_data(param, query_item).map(|(buffer_data, _)| buffer_data)
+    }
+
+    fn get_index_and_compare_data(
+        _: &SystemParamItem<Self::Param>,
+        _: (Entity, MainEntity),
+    ) -> Option<(NonMaxU32, Option<Self::CompareData>)> {
+        None
+    }
+
+    fn get_binned_index(
+        _: &SystemParamItem<Self::Param>,
+        _: (Entity, MainEntity),
+    ) -> Option<NonMaxU32> {
+        None
+    }
+
+    fn get_batch_indirect_parameters_index(
+        _: &SystemParamItem<Self::Param>,
+        _: &mut IndirectParametersBuffer,
+        _: (Entity, MainEntity),
+        _: u32,
+    ) -> Option<NonMaxU32> {
+        None
+    }
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct OutlineInstanceBindGroup(Option<BindGroup>);
+
+pub(crate) fn prepare_outline_instance_bind_group(
+    mut bind_group: ResMut<OutlineInstanceBindGroup>,
+    render_device: Res<RenderDevice>,
+    pipeline: Res<OutlinePipeline>,
+    instances: Res<BatchedInstanceBuffer<OutlineInstanceUniform>>,
+) {
+    bind_group.0 = instances.instance_data_binding().map(|binding| {
+        render_device.create_bind_group(
+            Some("outline instance bind group"),
+            &pipeline.instance_layout,
+            &[BindGroupEntry {
+                binding: 0,
+                resource: binding,
+            }],
+        )
+    });
+}
+
+pub(crate) struct SetOutlineInstanceBindGroup<const I: usize>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetOutlineInstanceBindGroup<I> {
+    type Param = SRes<OutlineInstanceBindGroup>;
+    type ViewQuery = ();
+    type ItemQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        _item_query: Option<()>,
+        bind_group: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let Some(bind_group) = bind_group.into_inner().0.as_ref() else {
+            return RenderCommandResult::Skip;
+        };
+        // Only set on platforms without storage buffers, where the instances are
+        // split into several uniform buffer bindings.
+        match item.extra_index().as_dynamic_offset() {
+            Some(offset) => pass.set_bind_group(I, bind_group, &[offset.get()]),
+            None => pass.set_bind_group(I, bind_group, &[]),
+        }
+        RenderCommandResult::Success
+    }
+}
+
+/// Sets the mesh bind group for the skins and morph targets of the outlined meshes.
+///
+/// The dynamic offset of the phase items points into the outline instances, not
+/// into the mesh uniforms, which the outline shader doesn't read: the mesh bind
+/// group is bound at the start of the mesh uniforms instead.
+pub(crate) struct SetOutlineMeshBindGroup<const I: usize>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetOutlineMeshBindGroup<I> {
+    type Param = <SetMeshBindGroup<I> as RenderCommand<OutlineMeshItem>>::Param;
+    type ViewQuery = <SetMeshBindGroup<I> as RenderCommand<OutlineMeshItem>>::ViewQuery;
+    type ItemQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view: ROQueryItem<'w, Self::ViewQuery>,
+        item_query: Option<()>,
+        param: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let mesh_item = OutlineMeshItem {
+            entity: (item.entity(), item.main_entity()),
+            draw_function: item.draw_function(),
+            batch_range: item.batch_range().clone(),
+            extra_index: match item.extra_index().as_dynamic_offset() {
+                Some(_) => PhaseItemExtraIndex::dynamic_offset(0),
+                None => item.extra_index(),
+            },
+        };
+        SetMeshBindGroup::<I>::render(&mesh_item, view, item_query, param, pass)
+    }
+}
+
+/// An outline phase item as seen by [`SetMeshBindGroup`].
+pub(crate) struct OutlineMeshItem {
+    entity: (Entity, MainEntity),
+    draw_function: DrawFunctionId,
+    batch_range: Range<u32>,
+    extra_index: PhaseItemExtraIndex,
+}
+
+impl PhaseItem for OutlineMeshItem {
+    fn entity(&self) -> Entity {
+        self.entity.0
+    }
+
+    fn main_entity(&self) -> MainEntity {
+        self.entity.1
+    }
+
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+
+    fn batch_range(&self) -> &Range<u32> {
+        &self.batch_range
+    }
+
+    fn batch_range_mut(&mut self) -> &mut Range<u32> {
+        &mut self.batch_range
+    }
+
+    fn extra_index(&self) -> PhaseItemExtraIndex {
+        self.extra_index
+    }
+
+    fn batch_range_and_extra_index_mut(&mut self) -> (&mut Range<u32>, &mut PhaseItemExtraIndex) {
+        (&mut self.batch_range, &mut self.extra_index)
+    }
+}
+
+/// The number of draw calls issued for extruded outlines in the last rendered
+/// frame, across all views.
+///
+/// Outlines of the same mesh are batched into a single draw call, so this is
+/// usually far lower than the number of outlined entities.
+#[derive(Resource, Clone, Default, Debug)]
+pub struct OutlineDrawCalls {
+    /// Incremented by the render world while drawing.
+    current: Arc<AtomicU32>,
+    last_frame: Arc<AtomicU32>,
+}
+
+impl OutlineDrawCalls {
+    pub fn get(&self) -> u32 {
+        self.last_frame.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) fn finish_outline_draw_calls(draw_calls: Res<OutlineDrawCalls>) {
+    let count = draw_calls.current.swap(0, Ordering::Relaxed);
+    draw_calls.last_frame.store(count, Ordering::Relaxed);
+}
+
+/// Counts the draws of the commands before it which succeeded.
+pub(crate) struct CountOutlineDrawCall;
+impl<P: PhaseItem> RenderCommand<P> for CountOutlineDrawCall {
+    type Param = SRes<OutlineDrawCalls>;
+    type ViewQuery = ();
+    type ItemQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        _item_query: Option<()>,
+        draw_calls: SystemParamItem<'w, '_, Self::Param>,
+        _pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        draw_calls.current.fetch_add(1, Ordering::Relaxed);
+        RenderCommandResult::Success
+    }
+}
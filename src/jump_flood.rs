@@ -0,0 +1,727 @@
+//! Screen-space outlines drawn with the jump flood algorithm.
+//!
+//! Outlined meshes are rendered into a mask, the distance from every pixel to
+//! the nearest masked pixel is computed with a few jump flood passes, and the
+//! outline is composited on top of the view target after the main pass.
+
+use bevy::{
+    core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    ecs::query::QueryItem,
+    pbr::MeshPipelineKey,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        mesh::{
+            allocator::MeshAllocator, MeshVertexBufferLayoutRef, RenderMesh, RenderMeshBufferInfo,
+        },
+        render_asset::RenderAssets,
+        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        render_resource::{
+            BindGroupEntry, BindGroupLayout, BindGroupLayoutEntry, BindingResource, BindingType,
+            BlendState, BufferBindingType, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            DynamicUniformBuffer, Extent3d, Face, FragmentState, FrontFace, LoadOp,
+            MultisampleState, Operations, PipelineCache, PolygonMode, PrimitiveState,
+            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            ShaderStages, ShaderType, SpecializedMeshPipeline, SpecializedMeshPipelineError,
+            SpecializedMeshPipelines, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            StoreOp, TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType,
+            TextureUsages, TextureViewDimension, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        sync_world::MainEntity,
+        texture::{CachedTexture, TextureCache},
+        view::{RenderVisibleEntities, ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
+        Extract,
+    },
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    window_size::ExtractedViewScaleFactor, DefaultOutlineMethod, Outline, OutlineMaterial,
+    OutlineMethod, OutlineRendered, OutlineStack, OutlineWidthMode,
+};
+
+/// The widest outline the jump flood passes can reach, in physical pixels.
+const MAX_JUMP_FLOOD_WIDTH: f32 = 32767.0;
+
+/// Texture storing, for every pixel, the position and width of the nearest seed.
+const SEED_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+/// Texture storing the outline color of every masked pixel.
+const COLOR_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct JumpFloodOutlineLabel;
+
+pub(crate) struct ExtractedJumpFloodOutline {
+    world_from_local: Mat4,
+    mesh: AssetId<Mesh>,
+    width: f32,
+    width_mode: OutlineWidthMode,
+    color: LinearRgba,
+}
+
+/// The outlined entities of the main world drawn with [`OutlineMethod::JumpFlood`].
+#[derive(Resource, Default)]
+pub(crate) struct ExtractedJumpFloodOutlines(HashMap<MainEntity, ExtractedJumpFloodOutline>);
+
+type OutlineQueryData = (
+    Entity,
+    &'static GlobalTransform,
+    &'static Mesh3d,
+    Option<&'static Outline>,
+    Option<&'static MeshMaterial3d<OutlineMaterial>>,
+    Option<&'static OutlineStack>,
+    &'static ViewVisibility,
+);
+
+pub(crate) fn extract_jump_flood_outlines(
+    mut extracted: ResMut<ExtractedJumpFloodOutlines>,
+    default_method: Extract<Res<DefaultOutlineMethod>>,
+    materials: Extract<Res<Assets<OutlineMaterial>>>,
+    q_outline: Extract<Query<OutlineQueryData, With<OutlineRendered>>>,
+) {
+    extracted.0.clear();
+    for (entity, transform, mesh, outline, material, stack, visibility) in q_outline.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let outline = outline.filter(|_| !stack.is_some_and(OutlineStack::overrides_base));
+        let outline = match (outline, material) {
+            (Some(outline), _) if outline.enabled => outline.clone(),
+            (Some(_), _) | (None, None) => continue,
+            (None, Some(material)) => match materials.get(material.id()) {
+                Some(material) => Outline::from(material),
+                None => continue,
+            },
+        };
+        if outline.method.unwrap_or(default_method.0) != OutlineMethod::JumpFlood {
+            continue;
+        }
+        extracted.0.insert(
+            entity.into(),
+            ExtractedJumpFloodOutline {
+                world_from_local: transform.compute_matrix(),
+                mesh: mesh.id(),
+                width: outline.width,
+                width_mode: outline.width_mode,
+                color: outline.color,
+            },
+        );
+    }
+}
+
+#[derive(Clone, ShaderType)]
+pub(crate) struct JumpFloodOutlineUniform {
+    world_from_local: Mat4,
+    color: Vec4,
+    width: f32,
+}
+
+#[derive(Clone, ShaderType)]
+pub(crate) struct JumpFloodStepUniform {
+    step: f32,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct JumpFloodOutlineMeta {
+    uniforms: DynamicUniformBuffer<JumpFloodOutlineUniform>,
+}
+
+struct JumpFloodDraw {
+    pipeline: CachedRenderPipelineId,
+    mesh: AssetId<Mesh>,
+    offset: u32,
+}
+
+/// The outlines a view draws with the jump flood method.
+#[derive(Component)]
+pub(crate) struct ViewJumpFloodOutlines {
+    draws: Vec<JumpFloodDraw>,
+    composite_pipeline: CachedRenderPipelineId,
+    /// Width of the widest outline in physical pixels, deciding how far to flood.
+    max_width: f32,
+}
+
+#[derive(Component)]
+pub(crate) struct ViewJumpFloodTextures {
+    color: CachedTexture,
+    seeds: [CachedTexture; 2],
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_jump_flood_outlines(
+    mut commands: Commands,
+    extracted: Res<ExtractedJumpFloodOutlines>,
+    render_meshes: Res<RenderAssets<RenderMesh>>,
+    mask_pipeline: Res<JumpFloodMaskPipeline>,
+    composite_pipeline: Res<JumpFloodCompositePipeline>,
+    mut mask_pipelines: ResMut<SpecializedMeshPipelines<JumpFloodMaskPipeline>>,
+    mut composite_pipelines: ResMut<SpecializedRenderPipelines<JumpFloodCompositePipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    mut meta: ResMut<JumpFloodOutlineMeta>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    views: Query<(
+        Entity,
+        &ViewTarget,
+        &RenderVisibleEntities,
+        Option<&ExtractedViewScaleFactor>,
+    )>,
+) {
+    meta.uniforms.clear();
+    for (view_entity, view_target, visible_entities, scale_factor) in views.iter() {
+        let visible: HashSet<MainEntity> = visible_entities
+            .get::<With<OutlineRendered>>()
+            .iter()
+            .map(|&(_, main_entity)| main_entity)
+            .collect();
+        let mut draws = vec![];
+        let mut max_width: f32 = 0.0;
+        for (main_entity, outline) in extracted.0.iter() {
+            if !visible.contains(main_entity) {
+                continue;
+            }
+            let Some(mesh) = render_meshes.get(outline.mesh) else {
+                continue;
+            };
+            let width = match outline.width_mode {
+                OutlineWidthMode::LogicalPixels => {
+                    outline.width * scale_factor.map_or(1.0, |scale_factor| scale_factor.0)
+                }
+                OutlineWidthMode::PhysicalPixels | OutlineWidthMode::World => outline.width,
+            }
+            .clamp(0.0, MAX_JUMP_FLOOD_WIDTH);
+            let key = MeshPipelineKey::from_primitive_topology(mesh.primitive_topology());
+            let pipeline =
+                match mask_pipelines.specialize(&pipeline_cache, &mask_pipeline, key, &mesh.layout)
+                {
+                    Ok(id) => id,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+            let offset = meta.uniforms.push(&JumpFloodOutlineUniform {
+                world_from_local: outline.world_from_local,
+                color: outline.color.to_vec4(),
+                width,
+            });
+            max_width = max_width.max(width);
+            draws.push(JumpFloodDraw {
+                pipeline,
+                mesh: outline.mesh,
+                offset,
+            });
+        }
+        if draws.is_empty() {
+            commands
+                .entity(view_entity)
+                .remove::<ViewJumpFloodOutlines>();
+            continue;
+        }
+        let composite_pipeline = composite_pipelines.specialize(
+            &pipeline_cache,
+            &composite_pipeline,
+            view_target.main_texture_format(),
+        );
+        commands.entity(view_entity).insert(ViewJumpFloodOutlines {
+            draws,
+            composite_pipeline,
+            max_width,
+        });
+    }
+    meta.uniforms.write_buffer(&render_device, &render_queue);
+}
+
+pub(crate) fn prepare_jump_flood_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    views: Query<(Entity, &ExtractedCamera), With<ViewJumpFloodOutlines>>,
+) {
+    for (view_entity, camera) in views.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        let mut texture = |label: &'static str, format: TextureFormat| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format,
+                    usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+            )
+        };
+        let color = texture("jump flood outline color texture", COLOR_TEXTURE_FORMAT);
+        let seeds = [
+            texture("jump flood outline seed texture a", SEED_TEXTURE_FORMAT),
+            texture("jump flood outline seed texture b", SEED_TEXTURE_FORMAT),
+        ];
+        commands
+            .entity(view_entity)
+            .insert(ViewJumpFloodTextures { color, seeds });
+    }
+}
+
+/// Renders the outlined meshes into the color and seed textures.
+#[derive(Resource)]
+pub(crate) struct JumpFloodMaskPipeline {
+    view_layout: BindGroupLayout,
+    outline_layout: BindGroupLayout,
+    shader_handle: Handle<Shader>,
+}
+
+impl FromWorld for JumpFloodMaskPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let view_layout = render_device.create_bind_group_layout(
+            Some("jump flood outline view layout"),
+            &[uniform_layout_entry::<ViewUniform>(
+                ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+            )],
+        );
+        let outline_layout = render_device.create_bind_group_layout(
+            Some("jump flood outline layout"),
+            &[uniform_layout_entry::<JumpFloodOutlineUniform>(
+                ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+            )],
+        );
+        let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
+            include_str!("render/jump_flood_mask.wgsl"),
+            "render/jump_flood_mask.wgsl",
+        ));
+        Self {
+            view_layout,
+            outline_layout,
+            shader_handle,
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for JumpFloodMaskPipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayoutRef,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let vertex_buffer_layout = layout
+            .0
+            .get_layout(&[Mesh::ATTRIBUTE_POSITION.at_shader_location(0)])?;
+        Ok(RenderPipelineDescriptor {
+            label: Some("jump_flood_outline_mask_pipeline".into()),
+            layout: vec![self.view_layout.clone(), self.outline_layout.clone()],
+            push_constant_ranges: vec![],
+            vertex: VertexState {
+                shader: self.shader_handle.clone(),
+                entry_point: "vertex".into(),
+                shader_defs: vec![],
+                buffers: vec![vertex_buffer_layout],
+            },
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: Some(Face::Back),
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: key.primitive_topology(),
+                strip_index_format: None,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader_handle.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![
+                    Some(ColorTargetState {
+                        format: COLOR_TEXTURE_FORMAT,
+                        blend: None,
+                        write_mask: ColorWrites::ALL,
+                    }),
+                    Some(ColorTargetState {
+                        format: SEED_TEXTURE_FORMAT,
+                        blend: None,
+                        write_mask: ColorWrites::ALL,
+                    }),
+                ],
+            }),
+            zero_initialize_workgroup_memory: true,
+        })
+    }
+}
+
+/// Propagates the nearest seed of every pixel, halving the step size each pass.
+#[derive(Resource)]
+pub(crate) struct JumpFloodPipeline {
+    layout: BindGroupLayout,
+    pipeline: CachedRenderPipelineId,
+    steps: DynamicUniformBuffer<JumpFloodStepUniform>,
+    /// Offsets of the step sizes `1, 2, 4, ...` inside `steps`.
+    step_offsets: Vec<u32>,
+}
+
+impl FromWorld for JumpFloodPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            Some("jump flood layout"),
+            &[
+                texture_layout_entry(0),
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    ..uniform_layout_entry::<JumpFloodStepUniform>(ShaderStages::FRAGMENT)
+                },
+            ],
+        );
+
+        let mut steps = DynamicUniformBuffer::default();
+        let step_offsets = (0..MAX_JUMP_FLOOD_WIDTH.log2().ceil() as u32)
+            .map(|i| {
+                steps.push(&JumpFloodStepUniform {
+                    step: (1 << i) as f32,
+                })
+            })
+            .collect();
+        steps.write_buffer(render_device, world.resource::<RenderQueue>());
+
+        let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
+            include_str!("render/jump_flood.wgsl"),
+            "render/jump_flood.wgsl",
+        ));
+        let pipeline =
+            world
+                .resource::<PipelineCache>()
+                .queue_render_pipeline(RenderPipelineDescriptor {
+                    label: Some("jump_flood_outline_pipeline".into()),
+                    layout: vec![layout.clone()],
+                    push_constant_ranges: vec![],
+                    vertex: fullscreen_shader_vertex_state(),
+                    primitive: PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: MultisampleState::default(),
+                    fragment: Some(FragmentState {
+                        shader: shader_handle,
+                        shader_defs: vec![],
+                        entry_point: "fragment".into(),
+                        targets: vec![Some(ColorTargetState {
+                            format: SEED_TEXTURE_FORMAT,
+                            blend: None,
+                            write_mask: ColorWrites::ALL,
+                        })],
+                    }),
+                    zero_initialize_workgroup_memory: true,
+                });
+        Self {
+            layout,
+            pipeline,
+            steps,
+            step_offsets,
+        }
+    }
+}
+
+/// Draws the outline of every pixel close enough to its nearest seed onto the view target.
+#[derive(Resource)]
+pub(crate) struct JumpFloodCompositePipeline {
+    layout: BindGroupLayout,
+    shader_handle: Handle<Shader>,
+}
+
+impl FromWorld for JumpFloodCompositePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            Some("jump flood composite layout"),
+            &[texture_layout_entry(0), texture_layout_entry(1)],
+        );
+        let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
+            include_str!("render/jump_flood_composite.wgsl"),
+            "render/jump_flood_composite.wgsl",
+        ));
+        Self {
+            layout,
+            shader_handle,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for JumpFloodCompositePipeline {
+    /// The format of the main texture of the view.
+    type Key = TextureFormat;
+
+    fn specialize(&self, format: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("jump_flood_outline_composite_pipeline".into()),
+            layout: vec![self.layout.clone()],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader_handle.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            zero_initialize_workgroup_memory: true,
+        }
+    }
+}
+
+fn uniform_layout_entry<T: ShaderType>(visibility: ShaderStages) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding: 0,
+        visibility,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: true,
+            min_binding_size: Some(T::min_size()),
+        },
+        count: None,
+    }
+}
+
+fn texture_layout_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::FRAGMENT,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: false },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct JumpFloodOutlineNode;
+
+impl ViewNode for JumpFloodOutlineNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewUniformOffset,
+        &'static ViewJumpFloodOutlines,
+        &'static ViewJumpFloodTextures,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, view_target, view_uniform_offset, outlines, textures): QueryItem<
+            'w,
+            Self::ViewQuery,
+        >,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let mask_pipeline = world.resource::<JumpFloodMaskPipeline>();
+        let jump_flood_pipeline = world.resource::<JumpFloodPipeline>();
+        let composite_pipeline = world.resource::<JumpFloodCompositePipeline>();
+        let meta = world.resource::<JumpFloodOutlineMeta>();
+        let render_meshes = world.resource::<RenderAssets<RenderMesh>>();
+        let mesh_allocator = world.resource::<MeshAllocator>();
+
+        let (
+            Some(flood_render_pipeline),
+            Some(composite_render_pipeline),
+            Some(view_binding),
+            Some(outline_binding),
+            Some(step_binding),
+        ) = (
+            pipeline_cache.get_render_pipeline(jump_flood_pipeline.pipeline),
+            pipeline_cache.get_render_pipeline(outlines.composite_pipeline),
+            world.resource::<ViewUniforms>().uniforms.binding(),
+            meta.uniforms.binding(),
+            jump_flood_pipeline.steps.binding(),
+        )
+        else {
+            return Ok(());
+        };
+
+        let render_device = render_context.render_device().clone();
+        let view_bind_group = render_device.create_bind_group(
+            Some("jump flood outline view bind group"),
+            &mask_pipeline.view_layout,
+            &[BindGroupEntry {
+                binding: 0,
+                resource: view_binding,
+            }],
+        );
+        let outline_bind_group = render_device.create_bind_group(
+            Some("jump flood outline bind group"),
+            &mask_pipeline.outline_layout,
+            &[BindGroupEntry {
+                binding: 0,
+                resource: outline_binding,
+            }],
+        );
+
+        // Render the outlined meshes into the color and seed textures.
+        {
+            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("jump_flood_outline_mask_pass"),
+                color_attachments: &[
+                    Some(RenderPassColorAttachment {
+                        view: &textures.color.default_view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(Default::default()),
+                            store: StoreOp::Store,
+                        },
+                    }),
+                    Some(RenderPassColorAttachment {
+                        view: &textures.seeds[0].default_view,
+                        resolve_target: None,
+                        ops: Operations {
+                            // Pixels without a seed are marked by a zero `w`.
+                            load: LoadOp::Clear(Default::default()),
+                            store: StoreOp::Store,
+                        },
+                    }),
+                ],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            if let Some(viewport) = camera.viewport.as_ref() {
+                pass.set_camera_viewport(viewport);
+            }
+            pass.set_bind_group(0, &view_bind_group, &[view_uniform_offset.offset]);
+            for draw in outlines.draws.iter() {
+                let (Some(pipeline), Some(mesh), Some(vertex_slice)) = (
+                    pipeline_cache.get_render_pipeline(draw.pipeline),
+                    render_meshes.get(draw.mesh),
+                    mesh_allocator.mesh_vertex_slice(&draw.mesh),
+                ) else {
+                    continue;
+                };
+                pass.set_render_pipeline(pipeline);
+                pass.set_bind_group(1, &outline_bind_group, &[draw.offset]);
+                pass.set_vertex_buffer(0, vertex_slice.buffer.slice(..));
+                match &mesh.buffer_info {
+                    RenderMeshBufferInfo::Indexed {
+                        index_format,
+                        count,
+                    } => {
+                        let Some(index_slice) = mesh_allocator.mesh_index_slice(&draw.mesh) else {
+                            continue;
+                        };
+                        pass.set_index_buffer(index_slice.buffer.slice(..), 0, *index_format);
+                        pass.draw_indexed(
+                            index_slice.range.start..(index_slice.range.start + *count),
+                            vertex_slice.range.start as i32,
+                            0..1,
+                        );
+                    }
+                    RenderMeshBufferInfo::NonIndexed => {
+                        pass.draw(vertex_slice.range, 0..1);
+                    }
+                }
+            }
+        }
+
+        // Flood the seeds, from the largest step down to single pixels.
+        let step_count = ((outlines.max_width.ceil() as u32 + 1).next_power_of_two())
+            .trailing_zeros()
+            .min(jump_flood_pipeline.step_offsets.len() as u32);
+        let mut source = 0;
+        for step in (0..step_count).rev() {
+            let bind_group = render_device.create_bind_group(
+                Some("jump flood bind group"),
+                &jump_flood_pipeline.layout,
+                &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(
+                            &textures.seeds[source].default_view,
+                        ),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: step_binding.clone(),
+                    },
+                ],
+            );
+            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("jump_flood_outline_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &textures.seeds[1 - source].default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Default::default()),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_render_pipeline(flood_render_pipeline);
+            pass.set_bind_group(
+                0,
+                &bind_group,
+                &[jump_flood_pipeline.step_offsets[step as usize]],
+            );
+            pass.draw(0..3, 0..1);
+            source = 1 - source;
+        }
+
+        // Draw the outlines on top of the view target.
+        let bind_group = render_device.create_bind_group(
+            Some("jump flood composite bind group"),
+            &composite_pipeline.layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&textures.seeds[source].default_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&textures.color.default_view),
+                },
+            ],
+        );
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("jump_flood_outline_composite_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: view_target.main_texture_view(),
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        pass.set_render_pipeline(composite_render_pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
@@ -1,52 +1,145 @@
 #![doc = include_str!("../README.md")]
 
+mod hierarchy;
+// `ShaderType` derives emit size checks that newer compilers report as dead code.
+#[allow(dead_code)]
+mod instance;
+#[allow(dead_code)]
+mod jump_flood;
 mod material;
+mod outline;
+mod phase;
 mod pipeline;
+#[allow(dead_code)]
+mod post_process;
 mod prepare;
 mod smooth_normal;
+mod stack;
+mod stencil;
+#[allow(dead_code)]
 mod window_size;
 
 #[cfg(feature = "picking")]
 pub mod picking;
 
 use bevy::{
-    core_pipeline::core_3d::Opaque3d,
+    core_pipeline::core_3d::graph::{Core3d, Node3d},
     pbr::{extract_mesh_materials, RenderMaterialInstances},
     prelude::*,
     render::{
+        batching::no_gpu_preprocessing::{
+            clear_batched_cpu_instance_buffers, write_batched_instance_buffer,
+            BatchedInstanceBuffer,
+        },
         extract_component::{ExtractComponent, ExtractComponentPlugin},
         mesh::MeshVertexAttribute,
         render_asset::{prepare_assets, RenderAssetPlugin},
-        render_phase::AddRenderCommand,
-        render_resource::{BufferDescriptor, BufferUsages, SpecializedMeshPipelines, VertexFormat},
+        render_graph::{RenderGraphApp, ViewNodeRunner},
+        render_phase::{
+            sort_phase_system, AddRenderCommand, BinnedRenderPhasePlugin, DrawFunctions,
+            SortedRenderPhasePlugin,
+        },
+        render_resource::{SpecializedMeshPipelines, SpecializedRenderPipelines, VertexFormat},
         renderer::RenderDevice,
         view::{self, VisibilitySystems},
         Render, RenderApp, RenderSet,
     },
 };
 
-pub use material::OutlineMaterial;
+use hierarchy::propagate_outline_hierarchy;
+pub use hierarchy::{InheritedOutline, OutlineHierarchy};
+pub use instance::OutlineDrawCalls;
+use instance::{
+    extract_outline_transforms, finish_outline_draw_calls, prepare_outline_instance_bind_group,
+    OutlineBatch, OutlineInstanceBindGroup, OutlineInstanceUniform, RenderOutlineTransforms,
+};
+use jump_flood::{
+    extract_jump_flood_outlines, prepare_jump_flood_outlines, prepare_jump_flood_textures,
+    ExtractedJumpFloodOutlines, JumpFloodCompositePipeline, JumpFloodMaskPipeline,
+    JumpFloodOutlineLabel, JumpFloodOutlineMeta, JumpFloodOutlineNode, JumpFloodPipeline,
+};
 use material::PreparedOutlineMaterial;
-pub use pipeline::OutlinePipeline;
+pub use material::{
+    OutlineFalloff, OutlineMaterial, OutlineMaterialKey, OutlineMethod, OutlineWidthMode,
+};
+pub use outline::Outline;
+use outline::{extract_outlines, prepare_outlines, ExtractedOutlines, RenderOutlineInstances};
+use phase::{
+    extract_outline_phases, OpaqueOutlineLabel, OpaqueOutlineNode, TransparentOutlineLabel,
+    TransparentOutlineNode,
+};
+pub use phase::{Outline3d, Outline3dBinKey, TransparentOutline3d};
 use pipeline::{queue_outlines, OutlinePipelineCommands};
-use prepare::prepare_outline_mesh;
+pub use pipeline::{OutlinePass, OutlinePipeline, OutlinePipelineKey};
+pub use post_process::OutlinePostProcess;
+use post_process::{
+    prepare_outline_post_process, OutlinePostProcessLabel, OutlinePostProcessMeta,
+    OutlinePostProcessNode, OutlinePostProcessPipeline,
+};
+use prepare::{prepare_outline_mesh, DerivedOutlineMeshes, GeneratedOutlineNormals};
+pub use prepare::{OutlineNormalStorage, OutlineSourceMesh, OutlineWeldTolerance};
+pub use smooth_normal::OutlineNormalWeighting;
+use stack::{adopt_base_outlines, apply_outline_stacks};
+pub use stack::{OutlineLayer, OutlineStack};
+pub use stencil::OutlineStencil3d;
+use stencil::{
+    allow_sampling_camera_depth, prepare_outline_stencil_textures, OutlineStencilDepthPipeline,
+    StencilOutlineLabel, StencilOutlineNode,
+};
 use window_size::{
-    extract_window_size, prepare_window_size, queue_window_size_bind_group,
+    extract_view_scale_factor, prepare_window_size, queue_window_size_bind_group,
     DoubleReciprocalWindowSizeMeta, DoubleReciprocalWindowSizeUniform, SetWindowSizeBindGroup,
 };
 
 pub const ATTRIBUTE_OUTLINE_NORMAL: MeshVertexAttribute =
     MeshVertexAttribute::new("OutlineNormal", 9885409170, VertexFormat::Float32x3);
 
-pub struct OutlinePlugin;
+#[derive(Default)]
+pub struct OutlinePlugin {
+    /// The method used to draw outlines whose [`OutlineMaterial::method`] is `None`.
+    pub default_method: OutlineMethod,
+    /// Where the outline normals computed for meshes are stored.
+    pub normal_storage: OutlineNormalStorage,
+    /// The distance under which vertices are welded when smoothing outline normals,
+    /// so nearly coincident vertices, e.g. at UV seams of exported meshes, don't split
+    /// the outline open. Only vertices at the exact same position are welded when zero.
+    pub weld_tolerance: f32,
+}
+
+/// The method used to draw outlines whose [`OutlineMaterial::method`] is `None`,
+/// set from [`OutlinePlugin::default_method`].
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct DefaultOutlineMethod(pub OutlineMethod);
 
 impl Plugin for OutlinePlugin {
     fn build(&self, app: &mut App) {
+        let default_method = DefaultOutlineMethod(self.default_method);
+        let draw_calls = OutlineDrawCalls::default();
         app.init_asset::<OutlineMaterial>()
+            .insert_resource(draw_calls.clone())
+            .insert_resource(default_method)
+            .insert_resource(self.normal_storage)
+            .insert_resource(OutlineWeldTolerance(self.weld_tolerance))
+            .init_resource::<DerivedOutlineMeshes>()
+            .init_resource::<GeneratedOutlineNormals>()
             .register_type::<MeshMaterial3d<OutlineMaterial>>()
             .add_plugins(RenderAssetPlugin::<PreparedOutlineMaterial>::default())
             .add_plugins(ExtractComponentPlugin::<OutlineRendered>::default())
-            .add_systems(PostUpdate, prepare_outline_mesh)
+            .add_plugins(ExtractComponentPlugin::<OutlinePostProcess>::default())
+            .add_plugins(BinnedRenderPhasePlugin::<Outline3d, OutlineBatch>::default())
+            .add_plugins(SortedRenderPhasePlugin::<TransparentOutline3d, OutlineBatch>::default())
+            .add_plugins(SortedRenderPhasePlugin::<OutlineStencil3d, OutlineBatch>::default())
+            .add_systems(
+                PostUpdate,
+                (propagate_outline_hierarchy, prepare_outline_mesh).chain(),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    (adopt_base_outlines, apply_outline_stacks).chain(),
+                    allow_sampling_camera_depth,
+                ),
+            )
             .add_systems(
                 PostUpdate,
                 view::check_visibility::<With<OutlineRendered>>
@@ -59,19 +152,63 @@ impl Plugin for OutlinePlugin {
         };
         render_app
             .init_resource::<RenderMaterialInstances<OutlineMaterial>>()
+            .init_resource::<ExtractedOutlines>()
+            .init_resource::<RenderOutlineInstances>()
+            .init_resource::<RenderOutlineTransforms>()
+            .init_resource::<OutlineInstanceBindGroup>()
+            .insert_resource(draw_calls)
             .init_resource::<SpecializedMeshPipelines<OutlinePipeline>>()
-            .add_render_command::<Opaque3d, OutlinePipelineCommands>()
+            .init_resource::<DoubleReciprocalWindowSizeMeta>()
+            .init_resource::<SpecializedMeshPipelines<JumpFloodMaskPipeline>>()
+            .init_resource::<SpecializedRenderPipelines<JumpFloodCompositePipeline>>()
+            .init_resource::<ExtractedJumpFloodOutlines>()
+            .init_resource::<JumpFloodOutlineMeta>()
+            .init_resource::<SpecializedRenderPipelines<OutlinePostProcessPipeline>>()
+            .init_resource::<OutlinePostProcessMeta>()
+            .init_resource::<SpecializedRenderPipelines<OutlineStencilDepthPipeline>>()
+            .insert_resource(default_method)
+            .init_resource::<DrawFunctions<Outline3d>>()
+            .add_render_command::<Outline3d, OutlinePipelineCommands>()
+            .init_resource::<DrawFunctions<TransparentOutline3d>>()
+            .add_render_command::<TransparentOutline3d, OutlinePipelineCommands>()
+            .init_resource::<DrawFunctions<OutlineStencil3d>>()
+            .add_render_command::<OutlineStencil3d, OutlinePipelineCommands>()
             .add_systems(
                 ExtractSchedule,
                 (
                     extract_mesh_materials::<OutlineMaterial>, // NOTE: out of render set?
-                    extract_window_size,
+                    extract_outlines,
+                    extract_outline_transforms,
+                    extract_view_scale_factor,
+                    extract_jump_flood_outlines,
+                    extract_outline_phases,
                 )
                     .in_set(RenderSet::ExtractCommands),
             )
             .add_systems(
                 Render,
-                prepare_window_size.in_set(RenderSet::PrepareResources),
+                (
+                    prepare_window_size,
+                    prepare_jump_flood_outlines,
+                    prepare_jump_flood_textures.after(prepare_jump_flood_outlines),
+                    prepare_outline_stencil_textures,
+                    prepare_outline_post_process,
+                )
+                    .in_set(RenderSet::PrepareResources),
+            )
+            .add_systems(Render, prepare_outlines.in_set(RenderSet::PrepareAssets))
+            .add_systems(
+                Render,
+                (
+                    sort_phase_system::<TransparentOutline3d>,
+                    sort_phase_system::<OutlineStencil3d>,
+                )
+                    .in_set(RenderSet::PhaseSort),
+            )
+            .add_systems(
+                Render,
+                write_batched_instance_buffer::<OutlineBatch>
+                    .in_set(RenderSet::PrepareResourcesFlush),
             )
             .add_systems(
                 Render,
@@ -81,28 +218,83 @@ impl Plugin for OutlinePlugin {
             )
             .add_systems(
                 Render,
-                queue_window_size_bind_group.in_set(RenderSet::Queue),
+                (
+                    queue_window_size_bind_group,
+                    prepare_outline_instance_bind_group,
+                )
+                    .in_set(RenderSet::PrepareBindGroups),
+            )
+            .add_systems(
+                Render,
+                (
+                    clear_batched_cpu_instance_buffers::<OutlineBatch>,
+                    finish_outline_draw_calls,
+                )
+                    .in_set(RenderSet::Cleanup)
+                    .after(RenderSet::Render),
+            )
+            .add_render_graph_node::<ViewNodeRunner<OpaqueOutlineNode>>(Core3d, OpaqueOutlineLabel)
+            .add_render_graph_node::<ViewNodeRunner<TransparentOutlineNode>>(
+                Core3d,
+                TransparentOutlineLabel,
+            )
+            .add_render_graph_node::<ViewNodeRunner<StencilOutlineNode>>(
+                Core3d,
+                StencilOutlineLabel,
+            )
+            .add_render_graph_node::<ViewNodeRunner<OutlinePostProcessNode>>(
+                Core3d,
+                OutlinePostProcessLabel,
+            )
+            .add_render_graph_node::<ViewNodeRunner<JumpFloodOutlineNode>>(
+                Core3d,
+                JumpFloodOutlineLabel,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::MainOpaquePass,
+                    OpaqueOutlineLabel,
+                    Node3d::MainTransmissivePass,
+                ),
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::MainTransparentPass,
+                    TransparentOutlineLabel,
+                    Node3d::EndMainPass,
+                ),
+            )
+            .add_render_graph_edges(
+                Core3d,
+                // The stencil outlines resolve the multisampled view target, so they
+                // must be drawn before the screen-space outlines.
+                (
+                    Node3d::EndMainPass,
+                    StencilOutlineLabel,
+                    OutlinePostProcessLabel,
+                    JumpFloodOutlineLabel,
+                    Node3d::Tonemapping,
+                ),
             );
     }
 
     fn finish(&self, app: &mut App) {
-        let render_device = app.world().resource::<RenderDevice>();
-        let buffer = render_device.create_buffer(&BufferDescriptor {
-            label: Some("window size uniform buffer"),
-            size: size_of::<DoubleReciprocalWindowSizeUniform>() as u64,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
         };
+        let instance_buffer = BatchedInstanceBuffer::<OutlineInstanceUniform>::new(
+            render_app.world().resource::<RenderDevice>(),
+        );
         render_app
+            .insert_resource(instance_buffer)
             .init_resource::<OutlinePipeline>()
-            .insert_resource(DoubleReciprocalWindowSizeMeta {
-                buffer,
-                bind_group: None,
-            });
+            .init_resource::<JumpFloodMaskPipeline>()
+            .init_resource::<JumpFloodPipeline>()
+            .init_resource::<JumpFloodCompositePipeline>()
+            .init_resource::<OutlinePostProcessPipeline>()
+            .init_resource::<OutlineStencilDepthPipeline>();
     }
 }
 
@@ -111,5 +303,5 @@ impl Plugin for OutlinePlugin {
 ///
 /// Note the [`ExtractComponent`] trait implementation. This is necessary to
 /// tell Bevy that this object should be pulled into the render world.
-#[derive(Clone, Component, ExtractComponent)]
+#[derive(Clone, Default, Component, ExtractComponent)]
 pub struct OutlineRendered;
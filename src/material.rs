@@ -1,57 +1,180 @@
 use bevy::{
-    ecs::system::{lifetimeless::SRes, SystemParamItem},
-    pbr::MaterialBindGroupId,
+    ecs::system::SystemParamItem,
     prelude::*,
     render::{
         render_asset::{PrepareAssetError, RenderAsset},
-        render_resource::{AsBindGroup, AsBindGroupError, BindGroup},
-        renderer::RenderDevice,
+        render_resource::AsBindGroup,
     },
 };
 
-use crate::OutlinePipeline;
+/// The unit in which [`OutlineMaterial::width`] is measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OutlineWidthMode {
+    /// Logical pixels, scaled by the scale factor of the render target, so the
+    /// outline looks the same on every monitor.
+    #[default]
+    LogicalPixels,
+    /// Physical pixels of the render target.
+    PhysicalPixels,
+    /// World units, so the outline gets thinner as the object moves away.
+    ///
+    /// The mesh is extruded along [`ATTRIBUTE_OUTLINE_NORMAL`](crate::ATTRIBUTE_OUTLINE_NORMAL)
+    /// transformed to world space, so the width does not depend on the scale of
+    /// the entity.
+    World,
+}
+
+/// The technique used to draw an outline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OutlineMethod {
+    /// Draw an extruded hull of the mesh behind it. Cheap, but breaks on hard-edged
+    /// meshes, meshes with open edges and very wide outlines.
+    #[default]
+    Extrusion,
+    /// Draw an extruded hull of the mesh, but only outside of the silhouettes of
+    /// outlined meshes, so concave meshes get clean external-only silhouettes. The
+    /// main depth buffer has no stencil, so these outlines are drawn after the main
+    /// pass against a copy of its depth, and ignore [`OutlineMaterial::draw_occluded`].
+    /// While such outlines exist, the depth textures of 3D cameras are also made
+    /// sampleable to be copied.
+    StencilExtrusion,
+    /// Render the mesh into a mask, compute the distance to it with the jump flood
+    /// algorithm and composite the outline in screen space. Supports outlines of
+    /// arbitrary width on any mesh, but is always drawn on top of the scene and
+    /// ignores [`OutlineMaterial::falloff`] and [`OutlineMaterial::draw_occluded`].
+    /// [`OutlineWidthMode::World`] is treated like [`OutlineWidthMode::PhysicalPixels`].
+    /// The mask is rendered in the bind pose, without skinning or morph targets, so
+    /// the outlines of animated characters do not follow their animation.
+    JumpFlood,
+}
+
+/// Interpolation of the width and alpha of an outline by its distance to the camera.
+///
+/// Closer than `near`, the outline has its full width and alpha. Farther than `far`,
+/// the width is scaled by `far_width_scale` and the alpha by `far_alpha`. The width is
+/// then clamped between `min_width` and `max_width`, in the unit of
+/// [`OutlineMaterial::width`]. The falloff is disabled when `far` is not greater than `near`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutlineFalloff {
+    pub near: f32,
+    pub far: f32,
+    pub far_width_scale: f32,
+    pub far_alpha: f32,
+    pub min_width: f32,
+    pub max_width: f32,
+}
+
+impl Default for OutlineFalloff {
+    fn default() -> Self {
+        Self {
+            near: 0.0,
+            far: 0.0,
+            far_width_scale: 1.0,
+            far_alpha: 1.0,
+            min_width: 0.0,
+            max_width: f32::MAX,
+        }
+    }
+}
+
+impl OutlineFalloff {
+    /// Whether the outline changes with the distance at all.
+    pub fn is_enabled(&self) -> bool {
+        self.far > self.near
+    }
+
+    /// Whether the outline gets translucent with the distance.
+    pub fn fades(&self) -> bool {
+        self.is_enabled() && self.far_alpha < 1.0
+    }
+}
 
 #[derive(Asset, TypePath, AsBindGroup, Debug, Clone)]
+#[bind_group_data(OutlineMaterialKey)]
 pub struct OutlineMaterial {
-    #[uniform(0)]
     pub width: f32,
-    #[uniform(1)]
     pub color: LinearRgba,
+    pub falloff: OutlineFalloff,
+    /// Color of the parts of the mesh and its outline hidden behind other geometry.
+    /// Only used when `draw_occluded` is set.
+    pub occluded_color: LinearRgba,
+    pub width_mode: OutlineWidthMode,
+    /// Whether to draw the outline through occluders, like x-ray vision.
+    pub draw_occluded: bool,
+    /// The technique used to draw this outline, or `None` to use the default method
+    /// of the [`OutlinePlugin`](crate::OutlinePlugin).
+    pub method: Option<OutlineMethod>,
+}
+
+impl Default for OutlineMaterial {
+    fn default() -> Self {
+        Self {
+            width: 1.0,
+            color: LinearRgba::BLACK,
+            falloff: OutlineFalloff::default(),
+            occluded_color: LinearRgba::new(0.0, 0.0, 0.0, 0.5),
+            width_mode: OutlineWidthMode::default(),
+            draw_occluded: false,
+            method: None,
+        }
+    }
 }
 
 impl Material for OutlineMaterial {}
 
+/// The part of an [`OutlineMaterial`] which the pipeline is specialized on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutlineMaterialKey {
+    pub width_mode: OutlineWidthMode,
+    /// Whether the outline may be translucent and must be blended in the transparent phase.
+    pub alpha_blend: bool,
+    pub draw_occluded: bool,
+}
+
+impl From<&OutlineMaterial> for OutlineMaterialKey {
+    fn from(material: &OutlineMaterial) -> Self {
+        Self {
+            width_mode: material.width_mode,
+            alpha_blend: material.color.alpha < 1.0 || material.falloff.fades(),
+            draw_occluded: material.draw_occluded,
+        }
+    }
+}
+
+/// The values of an [`OutlineMaterial`] or an [`Outline`](crate::Outline) in the
+/// render world, written to the outline instance buffer for every entity drawn with it.
+#[derive(Clone)]
 pub struct PreparedOutlineMaterial {
-    pub bind_group: BindGroup,
+    pub width: f32,
+    pub color: LinearRgba,
+    pub falloff: OutlineFalloff,
+    pub occluded_color: LinearRgba,
+    pub key: OutlineMaterialKey,
+    pub method: Option<OutlineMethod>,
+}
+
+impl From<&OutlineMaterial> for PreparedOutlineMaterial {
+    fn from(material: &OutlineMaterial) -> Self {
+        Self {
+            width: material.width,
+            color: material.color,
+            falloff: material.falloff,
+            occluded_color: material.occluded_color,
+            key: material.into(),
+            method: material.method,
+        }
+    }
 }
 
 impl RenderAsset for PreparedOutlineMaterial {
     type SourceAsset = OutlineMaterial;
 
-    type Param = (
-        SRes<RenderDevice>,
-        SRes<OutlinePipeline>,
-        <OutlineMaterial as AsBindGroup>::Param,
-    );
+    type Param = ();
 
     fn prepare_asset(
         material: Self::SourceAsset,
-        (render_device, pipeline, ref mut material_param): &mut SystemParamItem<Self::Param>,
+        _: &mut SystemParamItem<Self::Param>,
     ) -> Result<Self, PrepareAssetError<Self::SourceAsset>> {
-        match material.as_bind_group(&pipeline.material_layout, render_device, material_param) {
-            Ok(prepared) => Ok(PreparedOutlineMaterial {
-                bind_group: prepared.bind_group,
-            }),
-            Err(AsBindGroupError::RetryNextUpdate) => {
-                Err(PrepareAssetError::RetryNextUpdate(material))
-            }
-            Err(other) => Err(PrepareAssetError::AsBindGroupError(other)),
-        }
-    }
-}
-
-impl PreparedOutlineMaterial {
-    pub fn get_bind_group_id(&self) -> MaterialBindGroupId {
-        MaterialBindGroupId(Some(self.bind_group.id()))
+        Ok((&material).into())
     }
 }
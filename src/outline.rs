@@ -0,0 +1,141 @@
+use bevy::{
+    pbr::RenderMaterialInstances,
+    prelude::*,
+    render::{render_asset::RenderAssets, sync_world::MainEntity, Extract},
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    OutlineFalloff, OutlineMaterial, OutlineMethod, OutlineRendered, OutlineStack,
+    OutlineWidthMode, PreparedOutlineMaterial,
+};
+
+/// The outline of a mesh, as an alternative to a [`MeshMaterial3d<OutlineMaterial>`].
+///
+/// It is extracted straight to the render world, so outlines can be toggled and
+/// animated per entity without creating or modifying assets. When an entity has
+/// both, this component takes precedence over its material, unless a layer of
+/// its [`OutlineStack`] above the base one is drawn.
+#[derive(Component, Debug, Clone, PartialEq)]
+#[require(OutlineRendered)]
+pub struct Outline {
+    /// Whether the outline is drawn.
+    pub enabled: bool,
+    /// See [`OutlineMaterial::width`].
+    pub width: f32,
+    /// See [`OutlineMaterial::color`].
+    pub color: LinearRgba,
+    /// See [`OutlineMaterial::falloff`].
+    pub falloff: OutlineFalloff,
+    /// See [`OutlineMaterial::occluded_color`].
+    pub occluded_color: LinearRgba,
+    /// See [`OutlineMaterial::width_mode`].
+    pub width_mode: OutlineWidthMode,
+    /// See [`OutlineMaterial::draw_occluded`].
+    pub draw_occluded: bool,
+    /// See [`OutlineMaterial::method`].
+    pub method: Option<OutlineMethod>,
+}
+
+impl Default for Outline {
+    fn default() -> Self {
+        Self::from(&OutlineMaterial::default())
+    }
+}
+
+impl From<&OutlineMaterial> for Outline {
+    fn from(material: &OutlineMaterial) -> Self {
+        Self {
+            enabled: true,
+            width: material.width,
+            color: material.color,
+            falloff: material.falloff,
+            occluded_color: material.occluded_color,
+            width_mode: material.width_mode,
+            draw_occluded: material.draw_occluded,
+            method: material.method,
+        }
+    }
+}
+
+impl From<&Outline> for OutlineMaterial {
+    fn from(outline: &Outline) -> Self {
+        Self {
+            width: outline.width,
+            color: outline.color,
+            falloff: outline.falloff,
+            occluded_color: outline.occluded_color,
+            width_mode: outline.width_mode,
+            draw_occluded: outline.draw_occluded,
+            method: outline.method,
+        }
+    }
+}
+
+/// The [`Outline`] components of the main world.
+#[derive(Resource, Default)]
+pub(crate) struct ExtractedOutlines {
+    /// Outlines added or changed since the last extraction.
+    changed: Vec<(MainEntity, Outline)>,
+    /// Every entity with an outline, enabled or not.
+    live: HashSet<MainEntity>,
+}
+
+type OutlineQueryData = (
+    Entity,
+    Ref<'static, Outline>,
+    Option<Ref<'static, OutlineStack>>,
+);
+
+pub(crate) fn extract_outlines(
+    mut extracted: ResMut<ExtractedOutlines>,
+    q_outline: Extract<Query<OutlineQueryData>>,
+) {
+    extracted.changed.clear();
+    extracted.live.clear();
+    for (entity, outline, stack) in q_outline.iter() {
+        // The material of the top layer is drawn instead.
+        if stack.as_ref().is_some_and(|stack| stack.overrides_base()) {
+            continue;
+        }
+        // The outline is extracted again when it is no longer overridden.
+        if outline.is_changed() || stack.is_some_and(|stack| stack.is_changed()) {
+            extracted.changed.push((entity.into(), outline.clone()));
+        }
+        extracted.live.insert(entity.into());
+    }
+}
+
+/// The prepared [`Outline`] components, or `None` for disabled ones.
+#[derive(Resource, Default)]
+pub(crate) struct RenderOutlineInstances(HashMap<MainEntity, Option<PreparedOutlineMaterial>>);
+
+impl RenderOutlineInstances {
+    /// The outline drawn for an entity: its [`Outline`] component if it has one,
+    /// or else its [`OutlineMaterial`].
+    pub(crate) fn get<'a>(
+        &'a self,
+        entity: &MainEntity,
+        material_instances: &RenderMaterialInstances<OutlineMaterial>,
+        materials: &'a RenderAssets<PreparedOutlineMaterial>,
+    ) -> Option<&'a PreparedOutlineMaterial> {
+        match self.0.get(entity) {
+            Some(outline) => outline.as_ref(),
+            None => materials.get(*material_instances.get(entity)?),
+        }
+    }
+}
+
+pub(crate) fn prepare_outlines(
+    mut extracted: ResMut<ExtractedOutlines>,
+    mut instances: ResMut<RenderOutlineInstances>,
+) {
+    let ExtractedOutlines { changed, live } = &mut *extracted;
+    instances.0.retain(|entity, _| live.contains(entity));
+    for (entity, outline) in changed.drain(..) {
+        let prepared = outline
+            .enabled
+            .then(|| PreparedOutlineMaterial::from(&OutlineMaterial::from(&outline)));
+        instances.0.insert(entity, prepared);
+    }
+}
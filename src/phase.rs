@@ -0,0 +1,312 @@
+//! The phases the extruded outlines are drawn in.
+//!
+//! Outlines are batched with their own per-instance data, see [`crate::instance`],
+//! so they can't share the phases of the main pass, whose items are batched with
+//! the mesh uniforms. Opaque outlines are drawn right after the opaque main pass,
+//! so transparent meshes still blend over them, and translucent and occluded
+//! outlines after the transparent main pass.
+
+use std::ops::Range;
+
+use bevy::{
+    ecs::{entity::EntityHashSet, query::QueryItem},
+    math::FloatOrd,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        render_phase::{
+            BinnedPhaseItem, CachedRenderPipelinePhaseItem, DrawFunctionId, PhaseItem,
+            PhaseItemExtraIndex, SortedPhaseItem, TrackedRenderPass, ViewBinnedRenderPhases,
+            ViewSortedRenderPhases,
+        },
+        render_resource::{CachedRenderPipelineId, RenderPassDescriptor, StoreOp},
+        renderer::RenderContext,
+        sync_world::{MainEntity, RenderEntity},
+        view::{ViewDepthTexture, ViewTarget},
+        Extract,
+    },
+};
+
+use crate::OutlineStencil3d;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct OpaqueOutlineLabel;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct TransparentOutlineLabel;
+
+/// A phase item of an opaque outline drawn with [`OutlinePass::Visible`].
+///
+/// [`OutlinePass::Visible`]: crate::OutlinePass::Visible
+pub struct Outline3d {
+    pub key: Outline3dBinKey,
+    pub representative_entity: (Entity, MainEntity),
+    pub batch_range: Range<u32>,
+    pub extra_index: PhaseItemExtraIndex,
+}
+
+/// The data which must be identical for opaque outlines to be batched together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Outline3dBinKey {
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+    /// The mesh of the outlined entities.
+    pub asset_id: AssetId<Mesh>,
+}
+
+impl PhaseItem for Outline3d {
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.representative_entity.0
+    }
+
+    fn main_entity(&self) -> MainEntity {
+        self.representative_entity.1
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.key.draw_function
+    }
+
+    #[inline]
+    fn batch_range(&self) -> &Range<u32> {
+        &self.batch_range
+    }
+
+    #[inline]
+    fn batch_range_mut(&mut self) -> &mut Range<u32> {
+        &mut self.batch_range
+    }
+
+    #[inline]
+    fn extra_index(&self) -> PhaseItemExtraIndex {
+        self.extra_index
+    }
+
+    #[inline]
+    fn batch_range_and_extra_index_mut(&mut self) -> (&mut Range<u32>, &mut PhaseItemExtraIndex) {
+        (&mut self.batch_range, &mut self.extra_index)
+    }
+}
+
+impl BinnedPhaseItem for Outline3d {
+    type BinKey = Outline3dBinKey;
+
+    #[inline]
+    fn new(
+        key: Self::BinKey,
+        representative_entity: (Entity, MainEntity),
+        batch_range: Range<u32>,
+        extra_index: PhaseItemExtraIndex,
+    ) -> Self {
+        Self {
+            key,
+            representative_entity,
+            batch_range,
+            extra_index,
+        }
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for Outline3d {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.key.pipeline
+    }
+}
+
+/// A phase item of a translucent outline drawn with [`OutlinePass::Visible`], or
+/// of an outline drawn with [`OutlinePass::Occluded`].
+///
+/// [`OutlinePass::Visible`]: crate::OutlinePass::Visible
+/// [`OutlinePass::Occluded`]: crate::OutlinePass::Occluded
+pub struct TransparentOutline3d {
+    pub distance: f32,
+    pub pipeline: CachedRenderPipelineId,
+    pub entity: (Entity, MainEntity),
+    pub draw_function: DrawFunctionId,
+    pub batch_range: Range<u32>,
+    pub extra_index: PhaseItemExtraIndex,
+}
+
+impl PhaseItem for TransparentOutline3d {
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity.0
+    }
+
+    fn main_entity(&self) -> MainEntity {
+        self.entity.1
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+
+    #[inline]
+    fn batch_range(&self) -> &Range<u32> {
+        &self.batch_range
+    }
+
+    #[inline]
+    fn batch_range_mut(&mut self) -> &mut Range<u32> {
+        &mut self.batch_range
+    }
+
+    #[inline]
+    fn extra_index(&self) -> PhaseItemExtraIndex {
+        self.extra_index
+    }
+
+    #[inline]
+    fn batch_range_and_extra_index_mut(&mut self) -> (&mut Range<u32>, &mut PhaseItemExtraIndex) {
+        (&mut self.batch_range, &mut self.extra_index)
+    }
+}
+
+impl SortedPhaseItem for TransparentOutline3d {
+    type SortKey = FloatOrd;
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        FloatOrd(self.distance)
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for TransparentOutline3d {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+pub(crate) fn extract_outline_phases(
+    mut opaque_phases: ResMut<ViewBinnedRenderPhases<Outline3d>>,
+    mut transparent_phases: ResMut<ViewSortedRenderPhases<TransparentOutline3d>>,
+    mut stencil_phases: ResMut<ViewSortedRenderPhases<OutlineStencil3d>>,
+    q_camera: Extract<Query<(&RenderEntity, &Camera), With<Camera3d>>>,
+    mut live_entities: Local<EntityHashSet>,
+) {
+    live_entities.clear();
+    for (render_entity, camera) in q_camera.iter() {
+        if !camera.is_active {
+            continue;
+        }
+        opaque_phases.insert_or_clear(render_entity.id());
+        transparent_phases.insert_or_clear(render_entity.id());
+        stencil_phases.insert_or_clear(render_entity.id());
+        live_entities.insert(render_entity.id());
+    }
+    opaque_phases.retain(|entity, _| live_entities.contains(entity));
+    transparent_phases.retain(|entity, _| live_entities.contains(entity));
+    stencil_phases.retain(|entity, _| live_entities.contains(entity));
+}
+
+/// Begins a pass drawing outlines onto the view target, tested against the main
+/// depth buffer.
+fn begin_outline_pass<'w>(
+    render_context: &'w mut RenderContext,
+    label: &'static str,
+    camera: &ExtractedCamera,
+    view_target: &ViewTarget,
+    depth: &ViewDepthTexture,
+) -> TrackedRenderPass<'w> {
+    let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(view_target.get_color_attachment())],
+        depth_stencil_attachment: Some(depth.get_attachment(StoreOp::Store)),
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    });
+    if let Some(viewport) = camera.viewport.as_ref() {
+        pass.set_camera_viewport(viewport);
+    }
+    pass
+}
+
+#[derive(Default)]
+pub(crate) struct OpaqueOutlineNode;
+
+impl ViewNode for OpaqueOutlineNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+    );
+
+    fn run<'w>(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, view_target, depth): QueryItem<'w, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.view_entity();
+        let Some(opaque_phase) = world
+            .resource::<ViewBinnedRenderPhases<Outline3d>>()
+            .get(&view_entity)
+        else {
+            return Ok(());
+        };
+        if opaque_phase.is_empty() {
+            return Ok(());
+        }
+
+        let mut pass = begin_outline_pass(
+            render_context,
+            "outline_opaque_pass",
+            camera,
+            view_target,
+            depth,
+        );
+        if let Err(err) = opaque_phase.render(&mut pass, world, view_entity) {
+            error!("Error encountered while rendering the opaque outline phase {err:?}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TransparentOutlineNode;
+
+impl ViewNode for TransparentOutlineNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+    );
+
+    fn run<'w>(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, view_target, depth): QueryItem<'w, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.view_entity();
+        let Some(transparent_phase) = world
+            .resource::<ViewSortedRenderPhases<TransparentOutline3d>>()
+            .get(&view_entity)
+        else {
+            return Ok(());
+        };
+        if transparent_phase.items.is_empty() {
+            return Ok(());
+        }
+
+        let mut pass = begin_outline_pass(
+            render_context,
+            "outline_transparent_pass",
+            camera,
+            view_target,
+            depth,
+        );
+        if let Err(err) = transparent_phase.render(&mut pass, world, view_entity) {
+            error!("Error encountered while rendering the transparent outline phase {err:?}");
+        }
+        Ok(())
+    }
+}
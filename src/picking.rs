@@ -1,69 +1,511 @@
 use std::ops::Deref;
 
-use bevy::{ecs::system::Resource, prelude::*};
+use bevy::{
+    ecs::{
+        entity::EntityHashSet,
+        system::{Resource, SystemParam},
+    },
+    math::Vec3A,
+    picking::{
+        focus::HoverMap,
+        pointer::{PointerButton, PointerId},
+        PickSet,
+    },
+    prelude::*,
+    render::primitives::Aabb,
+    window::PrimaryWindow,
+};
 
-use crate::{OutlineMaterial, OutlineRendered};
+use crate::{stack::set_outline_layer, OutlineLayer, OutlineMaterial, OutlineRendered};
 
 /// `OutlineMaterial` handle resource used when object is hovered.
-/// If this resource does not exist in world, no outline will show.
+/// If this resource does not exist in world, no outline will show, unless set
+/// by the [`PickingOutlines`] of the object.
 #[derive(Deref, Resource)]
 pub struct HoverOutline(pub Handle<OutlineMaterial>);
 
 /// `OutlineMaterial` handle resource used when object is selected.
-/// If this resource does not exist in world, no outline will show.
+/// If this resource does not exist in world, no outline will show, unless set
+/// by the [`PickingOutlines`] of the object.
 #[derive(Deref, Resource)]
 pub struct SelectedOutline(pub Handle<OutlineMaterial>);
 
+/// Marker component of the selected entities.
+///
+/// Clicking an outlined entity, or a descendant of an [`OutlinePickingRoot`],
+/// selects it and deselects the others, clicking it while holding control or
+/// shift toggles it instead, and clicking anywhere else deselects all of them.
+/// It can also be inserted and removed directly.
+#[derive(Component, Clone, Copy, Debug, Default, Reflect)]
+#[reflect(Component, Default, Debug)]
+pub struct Selected;
+
 /// `OutlineMaterial` handle resource used when object is pressed or clicked.
-/// If this resource does not exist in world, no outline will show.
+/// If this resource does not exist in world, no outline will show, unless set
+/// by the [`PickingOutlines`] of the object.
 #[derive(Deref, Resource)]
 pub struct PressedOutline(pub Handle<OutlineMaterial>);
 
+/// Outlines of an object used instead of [`HoverOutline`], [`PressedOutline`]
+/// and [`SelectedOutline`], e.g. so that enemies glow red and allies green when
+/// hovered. The resources are still used for the outlines left to `None`.
+#[derive(Component, Clone, Debug, Default)]
+pub struct PickingOutlines {
+    pub hover: Option<Handle<OutlineMaterial>>,
+    pub pressed: Option<Handle<OutlineMaterial>>,
+    pub selected: Option<Handle<OutlineMaterial>>,
+}
+
+/// Outlines all the [`OutlineRendered`] descendants of this entity together
+/// when any of them is hovered, pressed or selected, e.g. the meshes of a glTF
+/// character, instead of only the one under the pointer.
+///
+/// The whole group is selected by adding [`Selected`] to this entity, and its
+/// [`PickingOutlines`] are used for all the descendants.
+#[derive(Component, Clone, Copy, Debug, Default, Reflect)]
+#[reflect(Component, Default, Debug)]
+pub struct OutlinePickingRoot;
+
+/// Resolves the entities outlined together when an entity is picked.
+#[derive(SystemParam)]
+struct OutlineGroups<'w, 's> {
+    q_children: Query<'w, 's, &'static Children>,
+    q_parent: Query<'w, 's, &'static Parent>,
+    q_root: Query<'w, 's, (), With<OutlinePickingRoot>>,
+    q_outline: Query<'w, 's, (), With<OutlineRendered>>,
+    q_outlines: Query<'w, 's, &'static PickingOutlines>,
+}
+
+impl OutlineGroups<'_, '_> {
+    /// The entity picked on behalf of `entity`: its closest
+    /// [`OutlinePickingRoot`], or itself if it's outlined.
+    fn picked(&self, entity: Entity) -> Option<Entity> {
+        std::iter::once(entity)
+            .chain(self.q_parent.iter_ancestors(entity))
+            .find(|&ancestor| self.q_root.contains(ancestor))
+            .or_else(|| self.q_outline.contains(entity).then_some(entity))
+    }
+
+    /// The entities outlined when `entity` is picked, or nothing if they are
+    /// outlined on behalf of an ancestor.
+    fn members(&self, entity: Entity) -> Vec<Entity> {
+        if self.picked(entity) != Some(entity) {
+            return Vec::new();
+        }
+        if !self.q_root.contains(entity) {
+            return vec![entity];
+        }
+        std::iter::once(entity)
+            .chain(self.q_children.iter_descendants(entity))
+            .filter(|&member| self.q_outline.contains(member))
+            .collect()
+    }
+
+    /// The outline of `entity` chosen by its [`PickingOutlines`], or `fallback`.
+    fn outline(
+        &self,
+        entity: Entity,
+        outline_of: fn(&PickingOutlines) -> &Option<Handle<OutlineMaterial>>,
+        fallback: Option<&Handle<OutlineMaterial>>,
+    ) -> Option<Handle<OutlineMaterial>> {
+        self.q_outlines
+            .get(entity)
+            .ok()
+            .and_then(|outlines| outline_of(outlines).clone())
+            .or_else(|| fallback.cloned())
+    }
+
+    /// Sets or removes a layer of the outlines of the members of `entity`.
+    fn set_layer(
+        &self,
+        commands: &mut Commands,
+        entity: Entity,
+        layer: OutlineLayer,
+        outline: Option<Handle<OutlineMaterial>>,
+    ) {
+        for member in self.members(entity) {
+            set_outline_layer(commands, member, layer, outline.clone());
+        }
+    }
+}
+
+/// The selection changed, either by picking or by inserting and removing
+/// [`Selected`] directly.
+#[derive(Event, Clone, Debug, Default)]
+pub struct SelectionChanged {
+    /// The entities which were selected.
+    pub added: Vec<Entity>,
+    /// The entities which were deselected.
+    pub removed: Vec<Entity>,
+}
+
 /// Outline picking plugin as an alternative to `HighlightablePickingPlugin` in `bevy_mod_picking`
+///
+/// The outlines are pushed as layers of the [`OutlineStack`](crate::OutlineStack)
+/// of the entities, so the outline of the game is restored once they're popped.
 pub struct OutlinePickingPlugin;
 
 impl Plugin for OutlinePickingPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(MeshPickingPlugin)
-            .add_observer(update_material_on::<Pointer<Over>, HoverOutline>)
-            .add_observer(clear_material_on::<Pointer<Out>>)
-            .add_observer(update_material_on::<Pointer<Down>, PressedOutline>)
-            .add_observer(update_material_on::<Pointer<Up>, HoverOutline>);
+            .register_type::<Selected>()
+            .register_type::<OutlinePickingRoot>()
+            .add_observer(push_layer_on::<Pointer<Over>, (), HoverOutline>(
+                OutlineLayer::Hovered,
+                |outlines| &outlines.hover,
+            ))
+            .add_observer(pop_layers_on::<Pointer<Out>, ()>(&[
+                OutlineLayer::Hovered,
+                OutlineLayer::Pressed,
+            ]))
+            .add_observer(push_layer_on::<Pointer<Down>, (), PressedOutline>(
+                OutlineLayer::Pressed,
+                |outlines| &outlines.pressed,
+            ))
+            .add_observer(pop_layers_on::<Pointer<Up>, ()>(&[OutlineLayer::Pressed]))
+            .add_observer(push_layer_on::<OnAdd, Selected, SelectedOutline>(
+                OutlineLayer::Selected,
+                |outlines| &outlines.selected,
+            ))
+            .add_observer(pop_layers_on::<OnRemove, Selected>(&[
+                OutlineLayer::Selected,
+            ]))
+            .add_observer(select_on_click)
+            .add_event::<SelectionChanged>()
+            .add_systems(PreUpdate, deselect_on_empty_click.after(PickSet::Focus))
+            .add_systems(PostUpdate, send_selection_changed);
     }
 }
 
-fn update_material_on<E, T>(
-    trigger: Trigger<E>,
-    outline: Option<Res<T>>,
-    mut commands: Commands,
-    q_outline: Query<Entity, With<OutlineRendered>>,
-) where
+fn push_layer_on<E, B, T>(
+    layer: OutlineLayer,
+    outline_of: fn(&PickingOutlines) -> &Option<Handle<OutlineMaterial>>,
+) -> impl Fn(Trigger<E, B>, Option<Res<T>>, Commands, OutlineGroups)
+where
     E: Event,
+    B: Bundle,
     T: Deref<Target = Handle<OutlineMaterial>> + Resource + Send + Sync + 'static,
 {
-    let Ok(entity) = q_outline.get(trigger.entity()) else {
+    move |trigger, outline, mut commands, groups| {
+        let entity = trigger.entity();
+        let fallback = outline.as_deref().map(|outline| &**outline);
+        if let Some(outline) = groups.outline(entity, outline_of, fallback) {
+            groups.set_layer(&mut commands, entity, layer, Some(outline));
+        }
+    }
+}
+
+fn pop_layers_on<E, B>(
+    layers: &'static [OutlineLayer],
+) -> impl Fn(Trigger<E, B>, Commands, OutlineGroups)
+where
+    E: Event,
+    B: Bundle,
+{
+    move |trigger, mut commands, groups| {
+        for &layer in layers {
+            groups.set_layer(&mut commands, trigger.entity(), layer, None);
+        }
+    }
+}
+
+fn multi_select(keys: Option<Res<ButtonInput<KeyCode>>>) -> bool {
+    keys.is_some_and(|keys| {
+        keys.any_pressed([
+            KeyCode::ControlLeft,
+            KeyCode::ControlRight,
+            KeyCode::ShiftLeft,
+            KeyCode::ShiftRight,
+        ])
+    })
+}
+
+fn select_on_click(
+    trigger: Trigger<Pointer<Click>>,
+    box_selection: Option<Res<BoxSelection>>,
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    mut commands: Commands,
+    groups: OutlineGroups,
+    q_selected: Query<Entity, With<Selected>>,
+) {
+    let target = trigger.event().target;
+    // The click bubbles up to the ancestors of the clicked entity.
+    if trigger.event().button != PointerButton::Primary || trigger.entity() != target {
         return;
-    };
-    let Some(mut entity_commands) = commands.get_entity(entity) else {
+    }
+    // The drag ended on the entity it started on, and selects the box instead.
+    if box_selection.is_some_and(|box_selection| box_selection.is_dragging()) {
+        return;
+    }
+    let picked = groups.picked(target);
+    if multi_select(keys) {
+        match picked {
+            Some(entity) if q_selected.contains(entity) => {
+                commands.entity(entity).remove::<Selected>();
+            }
+            Some(entity) => {
+                commands.entity(entity).insert(Selected);
+            }
+            None => {}
+        }
+        return;
+    }
+    for other in q_selected.iter().filter(|other| Some(*other) != picked) {
+        commands.entity(other).remove::<Selected>();
+    }
+    if let Some(entity) = picked.filter(|entity| !q_selected.contains(*entity)) {
+        commands.entity(entity).insert(Selected);
+    }
+}
+
+/// Deselects all entities when the primary button is clicked on nothing, which
+/// doesn't trigger any [`Pointer<Click>`].
+fn deselect_on_empty_click(
+    mouse: Option<Res<ButtonInput<MouseButton>>>,
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    box_selection: Option<Res<BoxSelection>>,
+    hover_map: Res<HoverMap>,
+    mut commands: Commands,
+    q_selected: Query<Entity, With<Selected>>,
+) {
+    if !mouse.is_some_and(|mouse| mouse.just_released(MouseButton::Left)) || multi_select(keys) {
+        return;
+    }
+    if box_selection.is_some_and(|box_selection| box_selection.is_dragging()) {
+        return;
+    }
+    if hover_map
+        .get(&PointerId::Mouse)
+        .is_some_and(|hits| !hits.is_empty())
+    {
         return;
+    }
+    for entity in q_selected.iter() {
+        commands.entity(entity).remove::<Selected>();
+    }
+}
+
+fn send_selection_changed(
+    q_added: Query<Entity, Added<Selected>>,
+    mut removed: RemovedComponents<Selected>,
+    mut events: EventWriter<SelectionChanged>,
+) {
+    let event = SelectionChanged {
+        added: q_added.iter().collect(),
+        removed: removed.read().collect(),
     };
-    if let Some(material) = outline.map(|res| res.clone()) {
-        entity_commands.insert(MeshMaterial3d(material));
+    if !event.added.is_empty() || !event.removed.is_empty() {
+        events.send(event);
     }
 }
 
-fn clear_material_on<E>(
-    trigger: Trigger<E>,
+/// Selects the outlined entities in a rectangle dragged with the left mouse
+/// button in the primary window, as in RTS games. Requires [`OutlinePickingPlugin`].
+///
+/// While dragging, the entities whose bounds overlap the rectangle are
+/// previewed with their hover outline. On release they replace the selection,
+/// or are added to it while holding control or shift.
+pub struct OutlineBoxSelectionPlugin {
+    /// The distance in logical pixels the pointer moves before a press becomes a
+    /// drag, so that clicks still select single entities.
+    pub drag_threshold: f32,
+}
+
+impl Default for OutlineBoxSelectionPlugin {
+    fn default() -> Self {
+        Self {
+            drag_threshold: 4.0,
+        }
+    }
+}
+
+impl Plugin for OutlineBoxSelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BoxSelection {
+            drag_threshold: self.drag_threshold,
+            ..default()
+        })
+        .add_systems(
+            PreUpdate,
+            update_box_selection
+                .after(PickSet::Focus)
+                .after(deselect_on_empty_click),
+        );
+    }
+}
+
+/// The state of the rectangle selection of [`OutlineBoxSelectionPlugin`].
+#[derive(Resource, Default, Debug)]
+pub struct BoxSelection {
+    drag_threshold: f32,
+    start: Option<Vec2>,
+    end: Vec2,
+    dragging: bool,
+    camera: Option<Entity>,
+    previewed: EntityHashSet,
+}
+
+impl BoxSelection {
+    /// The rectangle being dragged in logical pixels of the primary window,
+    /// e.g. to draw it in the UI.
+    pub fn rect(&self) -> Option<Rect> {
+        self.start
+            .filter(|_| self.dragging)
+            .map(|start| Rect::from_corners(start, self.end))
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.dragging
+    }
+
+    /// The entities previewed as selected, which are selected on release.
+    pub fn previewed(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.previewed.iter().copied()
+    }
+}
+
+/// The rectangle in viewport coordinates of `camera` covered by the bounds.
+fn viewport_bounds(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    aabb: &Aabb,
+    transform: &GlobalTransform,
+) -> Option<Rect> {
+    (0..8)
+        .map(|corner| {
+            let sign = Vec3A::new(
+                if corner & 1 == 0 { -1.0 } else { 1.0 },
+                if corner & 2 == 0 { -1.0 } else { 1.0 },
+                if corner & 4 == 0 { -1.0 } else { 1.0 },
+            );
+            transform.transform_point((aabb.center + sign * aabb.half_extents).into())
+        })
+        .filter_map(|corner| camera.world_to_viewport(camera_transform, corner).ok())
+        .fold(None, |bounds: Option<Rect>, point| {
+            Some(bounds.map_or(Rect::from_corners(point, point), |bounds| {
+                bounds.union_point(point)
+            }))
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_box_selection(
+    mut box_selection: ResMut<BoxSelection>,
+    mouse: Option<Res<ButtonInput<MouseButton>>>,
+    keys: Option<Res<ButtonInput<KeyCode>>>,
+    hover_outline: Option<Res<HoverOutline>>,
+    hover_map: Res<HoverMap>,
     mut commands: Commands,
-    q_outline: Query<Entity, With<OutlineRendered>>,
-) where
-    E: Event,
-{
-    let Ok(entity) = q_outline.get(trigger.entity()) else {
+    groups: OutlineGroups,
+    q_window: Query<&Window, With<PrimaryWindow>>,
+    q_camera: Query<(Entity, &Camera, &GlobalTransform)>,
+    q_bounds: Query<(Entity, &Aabb, &GlobalTransform, &ViewVisibility), With<OutlineRendered>>,
+    q_selected: Query<Entity, With<Selected>>,
+) {
+    let Some(mouse) = mouse else {
+        return;
+    };
+    let cursor = q_window
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position());
+    if mouse.just_pressed(MouseButton::Left) {
+        box_selection.start = cursor;
+        box_selection.dragging = false;
+        // The topmost camera under the cursor.
+        box_selection.camera = cursor.and_then(|cursor| {
+            q_camera
+                .iter()
+                .filter(|(_, camera, _)| camera.is_active)
+                .filter(|(_, camera, _)| {
+                    camera
+                        .logical_viewport_rect()
+                        .is_some_and(|rect| rect.contains(cursor))
+                })
+                .max_by_key(|(_, camera, _)| camera.order)
+                .map(|(entity, _, _)| entity)
+        });
+    }
+    let Some(start) = box_selection.start else {
         return;
     };
-    let Some(mut entity_commands) = commands.get_entity(entity) else {
+    if let Some(cursor) = cursor {
+        box_selection.end = cursor;
+    }
+    if start.distance(box_selection.end) >= box_selection.drag_threshold {
+        box_selection.dragging = true;
+    }
+    let released = !mouse.pressed(MouseButton::Left);
+    if !box_selection.dragging {
+        if released {
+            box_selection.start = None;
+        }
         return;
+    }
+
+    let mut boxed = EntityHashSet::default();
+    if let Some((_, camera, camera_transform)) = box_selection
+        .camera
+        .and_then(|camera| q_camera.get(camera).ok())
+    {
+        let offset = camera
+            .logical_viewport_rect()
+            .map_or(Vec2::ZERO, |rect| rect.min);
+        let rect = Rect::from_corners(start - offset, box_selection.end - offset);
+        for (entity, aabb, transform, visibility) in q_bounds.iter() {
+            if !visibility.get() {
+                continue;
+            }
+            let overlaps =
+                viewport_bounds(camera, camera_transform, aabb, transform).is_some_and(|bounds| {
+                    bounds.min.cmple(rect.max).all() && bounds.max.cmpge(rect.min).all()
+                });
+            if let Some(picked) = groups.picked(entity).filter(|_| overlaps) {
+                boxed.insert(picked);
+            }
+        }
+    }
+
+    // The entities under the pointer keep their hover outline.
+    let hovered: EntityHashSet = hover_map
+        .get(&PointerId::Mouse)
+        .into_iter()
+        .flat_map(|hits| hits.keys())
+        .filter_map(|&entity| groups.picked(entity))
+        .collect();
+    let hover_outline = hover_outline.as_deref().map(|outline| &outline.0);
+    let unpreviewed: Vec<_> = if released {
+        box_selection.previewed.drain().collect()
+    } else {
+        let unpreviewed = box_selection
+            .previewed
+            .difference(&boxed)
+            .copied()
+            .collect();
+        for &entity in boxed.difference(&box_selection.previewed) {
+            let outline = groups.outline(entity, |outlines| &outlines.hover, hover_outline);
+            groups.set_layer(&mut commands, entity, OutlineLayer::Hovered, outline);
+        }
+        box_selection.previewed = boxed.clone();
+        unpreviewed
     };
-    entity_commands.remove::<MeshMaterial3d<OutlineMaterial>>();
+    for entity in unpreviewed
+        .into_iter()
+        .filter(|entity| !hovered.contains(entity))
+    {
+        groups.set_layer(&mut commands, entity, OutlineLayer::Hovered, None);
+    }
+
+    if released {
+        if !multi_select(keys) {
+            for entity in q_selected.iter().filter(|entity| !boxed.contains(entity)) {
+                commands.entity(entity).remove::<Selected>();
+            }
+        }
+        for &entity in boxed.iter().filter(|&&entity| !q_selected.contains(entity)) {
+            commands.entity(entity).insert(Selected);
+        }
+        box_selection.start = None;
+        box_selection.dragging = false;
+    }
 }
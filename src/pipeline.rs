@@ -1,40 +1,77 @@
 use bevy::{
-    core_pipeline::core_3d::{Opaque3d, Opaque3dBinKey},
-    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    core_pipeline::{
+        oit::OrderIndependentTransparencySettings,
+        prepass::{DeferredPrepass, DepthPrepass, MotionVectorPrepass, NormalPrepass},
+    },
+    ecs::query::QueryItem,
     pbr::{
-        DrawMesh, MeshPipeline, MeshPipelineKey, RenderMaterialInstances, RenderMeshInstances,
-        SetMeshBindGroup, SetMeshViewBindGroup,
+        setup_morph_and_skinning_defs, DrawMesh, MeshPipeline, MeshPipelineKey,
+        RenderMaterialInstances, RenderMeshInstanceFlags, RenderMeshInstances,
+        SetMeshViewBindGroup,
     },
     prelude::*,
     render::{
         mesh::{MeshVertexBufferLayoutRef, RenderMesh},
         render_asset::RenderAssets,
         render_phase::{
-            BinnedRenderPhaseType, DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult,
-            SetItemPipeline, TrackedRenderPass, ViewBinnedRenderPhases,
+            BinnedRenderPhaseType, DrawFunctions, PhaseItemExtraIndex, SetItemPipeline,
+            ViewBinnedRenderPhases, ViewSortedRenderPhases,
         },
         render_resource::{
-            AsBindGroup, BindGroupLayout, BindGroupLayoutEntry, BindingType, BlendState,
-            BufferBindingType, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState,
-            DepthStencilState, Face, FragmentState, FrontFace, MultisampleState, PipelineCache,
-            PolygonMode, PrimitiveState, RenderPipelineDescriptor, ShaderStages, ShaderType,
+            binding_types::uniform_buffer, BindGroupLayout, BindGroupLayoutEntries, BlendState,
+            ColorTargetState, ColorWrites, CompareFunction, DepthBiasState, DepthStencilState,
+            Face, FragmentState, FrontFace, GpuArrayBuffer, MultisampleState, PipelineCache,
+            PolygonMode, PrimitiveState, RenderPipelineDescriptor, ShaderDefVal, ShaderStages,
             SpecializedMeshPipeline, SpecializedMeshPipelineError, SpecializedMeshPipelines,
-            StencilFaceState, StencilState, TextureFormat, VertexState,
+            StencilFaceState, StencilOperation, StencilState, TextureFormat, VertexBufferLayout,
+            VertexState,
         },
         renderer::RenderDevice,
-        view::{ExtractedView, RenderVisibleEntities},
+        view::{ExtractedView, RenderVisibleEntities, ViewTarget},
     },
 };
 
 use crate::{
-    DoubleReciprocalWindowSizeUniform, OutlineMaterial, OutlineRendered, PreparedOutlineMaterial,
+    instance::{
+        CountOutlineDrawCall, OutlineInstanceUniform, SetOutlineInstanceBindGroup,
+        SetOutlineMeshBindGroup,
+    },
+    outline::RenderOutlineInstances,
+    phase::{Outline3d, Outline3dBinKey, TransparentOutline3d},
+    stencil::{OutlineStencil3d, OUTLINE_STENCIL_FORMAT},
+    DefaultOutlineMethod, DoubleReciprocalWindowSizeUniform, OutlineMaterial, OutlineMaterialKey,
+    OutlineMethod, OutlineRendered, OutlineWidthMode, PreparedOutlineMaterial,
     SetWindowSizeBindGroup, ATTRIBUTE_OUTLINE_NORMAL,
 };
 
+/// The key used to specialize the [`OutlinePipeline`] for a mesh and a material.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutlinePipelineKey {
+    pub mesh_key: MeshPipelineKey,
+    pub material_key: OutlineMaterialKey,
+    pub pass: OutlinePass,
+}
+
+/// The passes an outline can be drawn in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutlinePass {
+    /// The extruded hull behind the mesh, in the main pass.
+    Visible,
+    /// The parts of the mesh and its outline hidden behind other geometry.
+    Occluded,
+    /// The mesh itself, marking its silhouette in the stencil buffer.
+    StencilMask,
+    /// The extruded hull outside of the silhouettes marked in the stencil buffer.
+    StencilHull,
+}
+
 #[derive(Resource)]
 pub struct OutlinePipeline {
     pub(crate) mesh_pipeline: MeshPipeline,
-    pub(crate) material_layout: BindGroupLayout,
+    pub(crate) instance_layout: BindGroupLayout,
+    /// The number of instances per uniform buffer binding, on platforms without
+    /// storage buffers.
+    pub(crate) instance_batch_size: Option<u32>,
     pub(crate) window_size_layout: BindGroupLayout,
     pub(crate) shader_handle: Handle<Shader>,
 }
@@ -43,19 +80,21 @@ impl FromWorld for OutlinePipeline {
     fn from_world(world: &mut World) -> Self {
         let mesh_pipeline = MeshPipeline::from_world(world);
         let render_device = world.resource::<RenderDevice>();
-        let material_layout = OutlineMaterial::bind_group_layout(render_device);
+        let instance_layout = render_device.create_bind_group_layout(
+            Some("outline instance layout"),
+            &BindGroupLayoutEntries::single(
+                ShaderStages::VERTEX,
+                GpuArrayBuffer::<OutlineInstanceUniform>::binding_layout(render_device),
+            ),
+        );
+        let instance_batch_size =
+            GpuArrayBuffer::<OutlineInstanceUniform>::batch_size(render_device);
         let window_size_layout = render_device.create_bind_group_layout(
             Some("window size layout"),
-            &[BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::VERTEX,
-                ty: BindingType::Buffer {
-                    ty: BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: Some(DoubleReciprocalWindowSizeUniform::min_size()),
-                },
-                count: None,
-            }],
+            &BindGroupLayoutEntries::single(
+                ShaderStages::VERTEX,
+                uniform_buffer::<DoubleReciprocalWindowSizeUniform>(true),
+            ),
         );
         let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
             include_str!("render/outline.wgsl"),
@@ -63,7 +102,8 @@ impl FromWorld for OutlinePipeline {
         ));
         Self {
             mesh_pipeline,
-            material_layout,
+            instance_layout,
+            instance_batch_size,
             window_size_layout,
             shader_handle,
         }
@@ -71,86 +111,215 @@ impl FromWorld for OutlinePipeline {
 }
 
 impl SpecializedMeshPipeline for OutlinePipeline {
-    type Key = MeshPipelineKey;
+    type Key = OutlinePipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayoutRef,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let vertex_attributes = vec![
+        let mut vertex_attributes = vec![
             Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
             ATTRIBUTE_OUTLINE_NORMAL.at_shader_location(1),
         ];
-        let shader_defs = vec![
+        let mut shader_defs = vec![
             "MESH_PIPELINE".into(),
             "VERTEX_OUTPUT_INSTANCE_INDEX".into(),
         ];
-        let vertex_buffer_layout = layout.0.get_layout(&vertex_attributes)?;
+        if let Some(batch_size) = self.instance_batch_size {
+            shader_defs.push(ShaderDefVal::UInt(
+                "PER_OBJECT_BUFFER_BATCH_SIZE".into(),
+                batch_size,
+            ));
+        }
+        shader_defs.push(
+            match key.material_key.width_mode {
+                OutlineWidthMode::LogicalPixels => "OUTLINE_WIDTH_LOGICAL_PIXELS",
+                OutlineWidthMode::PhysicalPixels => "OUTLINE_WIDTH_PHYSICAL_PIXELS",
+                OutlineWidthMode::World => "OUTLINE_WIDTH_WORLD",
+            }
+            .into(),
+        );
+        match key.pass {
+            OutlinePass::Occluded => shader_defs.push("OUTLINE_OCCLUDED".into()),
+            OutlinePass::StencilMask => shader_defs.push("OUTLINE_STENCIL_MASK".into()),
+            OutlinePass::Visible | OutlinePass::StencilHull => {}
+        }
 
-        let view_layout = self.mesh_pipeline.get_view_layout(key.into()).clone();
+        let mesh_key = key.mesh_key;
+        let view_layout = self.mesh_pipeline.get_view_layout(mesh_key.into()).clone();
 
-        let mesh_layout = self.mesh_pipeline.mesh_layouts.model_only.clone();
+        // Adds the joint attributes for skinned meshes and the morph targets for
+        // meshes with the `MORPH_TARGETS` key, along with the matching mesh layout.
+        let mesh_layout = setup_morph_and_skinning_defs(
+            &self.mesh_pipeline.mesh_layouts,
+            layout,
+            2,
+            &mesh_key,
+            &mut shader_defs,
+            &mut vertex_attributes,
+        );
+        let vertex_buffer_layout = layout.0.get_layout(&vertex_attributes)?;
 
         let bind_group_layout = vec![
             view_layout,
             mesh_layout,
-            self.material_layout.clone(),
+            self.instance_layout.clone(),
             self.window_size_layout.clone(),
         ];
 
-        Ok(RenderPipelineDescriptor {
-            label: Some("outline_mesh_pipeline".into()),
-            layout: bind_group_layout,
-            push_constant_ranges: vec![],
-            vertex: VertexState {
-                shader: self.shader_handle.clone(),
-                entry_point: "vertex".into(),
-                shader_defs: shader_defs.clone(),
-                buffers: vec![vertex_buffer_layout],
-            },
-            primitive: PrimitiveState {
-                front_face: FrontFace::Ccw,
-                cull_mode: Some(Face::Front),
-                unclipped_depth: false,
-                polygon_mode: PolygonMode::Fill,
-                conservative: false,
-                topology: key.primitive_topology(),
-                strip_index_format: None,
-            },
-            depth_stencil: Some(DepthStencilState {
+        Ok(outline_pipeline_descriptor(
+            key,
+            self.shader_handle.clone(),
+            shader_defs,
+            bind_group_layout,
+            vertex_buffer_layout,
+        ))
+    }
+}
+
+/// Builds the descriptor of the outline pipeline for `key` from the layouts and
+/// shader defs resolved by [`OutlinePipeline::specialize`].
+fn outline_pipeline_descriptor(
+    key: OutlinePipelineKey,
+    shader: Handle<Shader>,
+    shader_defs: Vec<ShaderDefVal>,
+    layout: Vec<BindGroupLayout>,
+    vertex_buffer_layout: VertexBufferLayout,
+) -> RenderPipelineDescriptor {
+    let mesh_key = key.mesh_key;
+    let alpha_blend = key.material_key.alpha_blend || key.pass != OutlinePass::Visible;
+    let no_bias = DepthBiasState {
+        constant: 0,
+        slope_scale: 0.0,
+        clamp: 0.0,
+    };
+    let (cull_mode, depth_stencil) = match key.pass {
+        OutlinePass::Visible => (
+            Face::Front,
+            DepthStencilState {
                 format: TextureFormat::Depth32Float,
-                depth_write_enabled: true,
+                depth_write_enabled: !alpha_blend,
                 depth_compare: CompareFunction::Greater,
-                stencil: StencilState {
-                    front: StencilFaceState::IGNORE,
-                    back: StencilFaceState::IGNORE,
-                    read_mask: 0,
-                    write_mask: 0,
-                },
+                stencil: StencilState::default(),
+                bias: no_bias,
+            },
+        ),
+        // The occluded pass draws the front faces of the hull, which lie in
+        // front of the mesh itself, so only occluded parts fail the depth test.
+        OutlinePass::Occluded => (
+            Face::Back,
+            DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::Less,
+                stencil: StencilState::default(),
+                // Pull the hull towards the camera to avoid fighting with the mesh.
                 bias: DepthBiasState {
-                    constant: 0,
-                    slope_scale: 0.0,
+                    constant: 2,
+                    slope_scale: 1.0,
                     clamp: 0.0,
                 },
-            }),
-            multisample: MultisampleState {
-                count: key.msaa_samples(),
-                mask: !0,
-                alpha_to_coverage_enabled: false,
             },
-            fragment: Some(FragmentState {
-                shader: self.shader_handle.clone(),
-                shader_defs: shader_defs.clone(),
-                entry_point: "fragment".into(),
-                targets: vec![Some(ColorTargetState {
-                    format: TextureFormat::bevy_default(),
-                    blend: Some(BlendState::REPLACE),
-                    write_mask: ColorWrites::ALL,
-                })],
-            }),
-            zero_initialize_workgroup_memory: true,
-        })
+        ),
+        // The stencil passes are tested against a copy of the depth of the scene,
+        // which already holds the mesh itself, so only its visible parts are marked.
+        OutlinePass::StencilMask => (
+            Face::Back,
+            DepthStencilState {
+                format: OUTLINE_STENCIL_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: stencil_state(CompareFunction::Always, StencilOperation::Replace),
+                bias: no_bias,
+            },
+        ),
+        OutlinePass::StencilHull => (
+            Face::Front,
+            DepthStencilState {
+                format: OUTLINE_STENCIL_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::Greater,
+                stencil: stencil_state(CompareFunction::NotEqual, StencilOperation::Keep),
+                bias: no_bias,
+            },
+        ),
+    };
+
+    RenderPipelineDescriptor {
+        label: Some("outline_mesh_pipeline".into()),
+        layout,
+        push_constant_ranges: vec![],
+        vertex: VertexState {
+            shader: shader.clone(),
+            entry_point: "vertex".into(),
+            shader_defs: shader_defs.clone(),
+            buffers: vec![vertex_buffer_layout],
+        },
+        primitive: PrimitiveState {
+            front_face: FrontFace::Ccw,
+            cull_mode: Some(cull_mode),
+            unclipped_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+            topology: mesh_key.primitive_topology(),
+            strip_index_format: None,
+        },
+        depth_stencil: Some(depth_stencil),
+        multisample: MultisampleState {
+            count: mesh_key.msaa_samples(),
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        fragment: Some(FragmentState {
+            shader: shader.clone(),
+            shader_defs,
+            entry_point: "fragment".into(),
+            targets: vec![Some(ColorTargetState {
+                format: color_target_format(mesh_key),
+                blend: Some(if alpha_blend {
+                    BlendState::ALPHA_BLENDING
+                } else {
+                    BlendState::REPLACE
+                }),
+                write_mask: if key.pass == OutlinePass::StencilMask {
+                    ColorWrites::empty()
+                } else {
+                    ColorWrites::ALL
+                },
+            })],
+        }),
+        zero_initialize_workgroup_memory: true,
+    }
+}
+
+/// Compares the stencil buffer with the reference set by the stencil outline node on
+/// both faces, applying `pass_op` where the comparison passes.
+fn stencil_state(compare: CompareFunction, pass_op: StencilOperation) -> StencilState {
+    let face = StencilFaceState {
+        compare,
+        fail_op: StencilOperation::Keep,
+        depth_fail_op: StencilOperation::Keep,
+        pass_op,
+    };
+    StencilState {
+        front: face,
+        back: face,
+        read_mask: !0,
+        write_mask: if pass_op == StencilOperation::Keep {
+            0
+        } else {
+            !0
+        },
+    }
+}
+
+/// The format of the main texture of the view the outline is drawn to.
+fn color_target_format(mesh_key: MeshPipelineKey) -> TextureFormat {
+    if mesh_key.contains(MeshPipelineKey::HDR) {
+        ViewTarget::TEXTURE_FORMAT_HDR
+    } else {
+        TextureFormat::bevy_default()
     }
 }
 
@@ -159,77 +328,97 @@ pub(crate) type OutlinePipelineCommands = (
     SetItemPipeline,
     // Set the view uniform at bind group 0
     SetMeshViewBindGroup<0>,
-    // Set the mesh uniform at bind group 1
-    SetMeshBindGroup<1>,
-    // Set the material uniform at bind group 2
-    SetOutlineMaterialBindGroup<2>,
+    // Set the skins and morph targets at bind group 1
+    SetOutlineMeshBindGroup<1>,
+    // Set the outline instances at bind group 2
+    SetOutlineInstanceBindGroup<2>,
     // Set the window size uniform at bind group 3
     SetWindowSizeBindGroup<3>,
     // Draw the mesh
     DrawMesh,
+    // Count the draw call
+    CountOutlineDrawCall,
 );
 
-pub(crate) struct SetOutlineMaterialBindGroup<const I: usize>;
-impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetOutlineMaterialBindGroup<I> {
-    type Param = (
-        SRes<RenderAssets<PreparedOutlineMaterial>>,
-        SRes<RenderMaterialInstances<OutlineMaterial>>,
+/// The components of a view which the layout of its mesh view bind group depends on.
+type ViewLayoutQueryData = (
+    Has<DepthPrepass>,
+    Has<NormalPrepass>,
+    Has<MotionVectorPrepass>,
+    Has<DeferredPrepass>,
+    Has<OrderIndependentTransparencySettings>,
+);
+
+/// The part of the [`MeshPipelineKey`] of a view which selects the layouts of the
+/// mesh view and mesh bind groups set for it, so the pipelines match them.
+fn view_layout_key(
+    (depth_prepass, normal_prepass, motion_vector_prepass, deferred_prepass, oit): QueryItem<
+        ViewLayoutQueryData,
+    >,
+) -> MeshPipelineKey {
+    let mut key = MeshPipelineKey::NONE;
+    key.set(MeshPipelineKey::DEPTH_PREPASS, depth_prepass);
+    key.set(MeshPipelineKey::NORMAL_PREPASS, normal_prepass);
+    key.set(
+        MeshPipelineKey::MOTION_VECTOR_PREPASS,
+        motion_vector_prepass,
     );
-    type ViewQuery = ();
-    type ItemQuery = ();
-
-    #[inline]
-    fn render<'w>(
-        item: &P,
-        _view: (),
-        _item_query: Option<()>,
-        (materials, material_instances): SystemParamItem<'w, '_, Self::Param>,
-        pass: &mut TrackedRenderPass<'w>,
-    ) -> RenderCommandResult {
-        let materials = materials.into_inner();
-        let material_instances = material_instances.into_inner();
-
-        let Some(material_asset_id) = material_instances.get(&item.main_entity()) else {
-            return RenderCommandResult::Skip;
-        };
-        let Some(material) = materials.get(*material_asset_id) else {
-            return RenderCommandResult::Skip;
-        };
-        pass.set_bind_group(I, &material.bind_group, &[]);
-        RenderCommandResult::Success
-    }
+    key.set(MeshPipelineKey::DEFERRED_PREPASS, deferred_prepass);
+    key.set(MeshPipelineKey::OIT_ENABLED, oit);
+    key
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_outlines(
-    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    opaque_3d_draw_functions: Res<DrawFunctions<Outline3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<TransparentOutline3d>>,
     render_meshes: Res<RenderAssets<RenderMesh>>,
     render_material_instances: Res<RenderMaterialInstances<OutlineMaterial>>,
     render_materials: Res<RenderAssets<PreparedOutlineMaterial>>,
+    render_outline_instances: Res<RenderOutlineInstances>,
+    default_method: Res<DefaultOutlineMethod>,
     outline_pipeline: Res<OutlinePipeline>,
     mut pipelines: ResMut<SpecializedMeshPipelines<OutlinePipeline>>,
     pipeline_cache: Res<PipelineCache>,
-    mut opaque_render_phases: ResMut<ViewBinnedRenderPhases<Opaque3d>>,
+    mut opaque_render_phases: ResMut<ViewBinnedRenderPhases<Outline3d>>,
+    mut transparent_render_phases: ResMut<ViewSortedRenderPhases<TransparentOutline3d>>,
+    stencil_3d_draw_functions: Res<DrawFunctions<OutlineStencil3d>>,
+    mut stencil_render_phases: ResMut<ViewSortedRenderPhases<OutlineStencil3d>>,
     render_mesh_instances: Res<RenderMeshInstances>,
-    views: Query<(Entity, &RenderVisibleEntities, &Msaa), With<ExtractedView>>,
+    views: Query<(
+        Entity,
+        &ExtractedView,
+        &RenderVisibleEntities,
+        &Msaa,
+        ViewLayoutQueryData,
+    )>,
 ) {
-    let draw_function_id = opaque_3d_draw_functions
+    let draw_opaque_outline = opaque_3d_draw_functions
+        .read()
+        .id::<OutlinePipelineCommands>();
+    let draw_transparent_outline = transparent_3d_draw_functions
+        .read()
+        .id::<OutlinePipelineCommands>();
+    let draw_stencil_outline = stencil_3d_draw_functions
         .read()
         .id::<OutlinePipelineCommands>();
 
-    for (view_entity, view_visible_entities, msaa) in views.iter() {
-        let Some(opaque_phase) = opaque_render_phases.get_mut(&view_entity) else {
+    for (view_entity, view, view_visible_entities, msaa, view_layout) in views.iter() {
+        let (Some(opaque_phase), Some(transparent_phase)) = (
+            opaque_render_phases.get_mut(&view_entity),
+            transparent_render_phases.get_mut(&view_entity),
+        ) else {
             continue;
         };
+        let rangefinder = view.rangefinder3d();
 
-        let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+        let view_key = MeshPipelineKey::from_msaa_samples(msaa.samples())
+            | MeshPipelineKey::from_hdr(view.hdr)
+            | view_layout_key(view_layout);
 
         for &(render_entity, visible_entity) in
             view_visible_entities.get::<With<OutlineRendered>>().iter()
         {
-            let Some(material_asset_id) = render_material_instances.get(&visible_entity) else {
-                continue;
-            };
-
             let Some(mesh_instance) = render_mesh_instances.render_mesh_queue_data(visible_entity)
             else {
                 continue;
@@ -239,41 +428,159 @@ pub(crate) fn queue_outlines(
                 continue;
             };
 
-            let Some(material) = render_materials.get(*material_asset_id) else {
+            let Some(material) = render_outline_instances.get(
+                &visible_entity,
+                &render_material_instances,
+                &render_materials,
+            ) else {
                 continue;
             };
 
-            let mesh_key = view_key
-                | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology());
+            let method = material.method.unwrap_or(default_method.0);
+            // Drawn in screen space by the jump flood node instead.
+            if method == OutlineMethod::JumpFlood {
+                continue;
+            }
 
-            let pipeline_id = match pipelines.specialize(
-                &pipeline_cache,
-                &outline_pipeline,
+            // The topology of the mesh and whether it has morph targets.
+            let mesh_key = view_key | MeshPipelineKey::from_bits_retain(mesh.key_bits.bits());
+            let key = OutlinePipelineKey {
                 mesh_key,
-                &mesh.layout,
-            ) {
-                Ok(id) => id,
-                Err(err) => {
-                    error!("{}", err);
-                    return;
-                }
+                material_key: material.key,
+                pass: OutlinePass::Visible,
             };
 
-            mesh_instance
-                .material_bind_group_id
-                .set(material.get_bind_group_id());
+            if method == OutlineMethod::StencilExtrusion {
+                let Some(stencil_phase) = stencil_render_phases.get_mut(&view_entity) else {
+                    continue;
+                };
+                let distance = rangefinder.distance_translation(&mesh_instance.translation);
+                for pass in [OutlinePass::StencilMask, OutlinePass::StencilHull] {
+                    match pipelines.specialize(
+                        &pipeline_cache,
+                        &outline_pipeline,
+                        OutlinePipelineKey { pass, ..key },
+                        &mesh.layout,
+                    ) {
+                        Ok(pipeline) => stencil_phase.add(OutlineStencil3d {
+                            distance,
+                            mask: pass == OutlinePass::StencilMask,
+                            pipeline,
+                            entity: (render_entity, visible_entity),
+                            draw_function: draw_stencil_outline,
+                            batch_range: 0..1,
+                            extra_index: PhaseItemExtraIndex::NONE,
+                        }),
+                        Err(err) => error!("{}", err),
+                    }
+                }
+                continue;
+            }
+
+            let pipeline_id =
+                match pipelines.specialize(&pipeline_cache, &outline_pipeline, key, &mesh.layout) {
+                    Ok(id) => id,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+
+            let distance = rangefinder.distance_translation(&mesh_instance.translation);
+
+            if material.key.draw_occluded {
+                let occluded_key = OutlinePipelineKey {
+                    pass: OutlinePass::Occluded,
+                    ..key
+                };
+                match pipelines.specialize(
+                    &pipeline_cache,
+                    &outline_pipeline,
+                    occluded_key,
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline) => transparent_phase.add(TransparentOutline3d {
+                        entity: (render_entity, visible_entity),
+                        draw_function: draw_transparent_outline,
+                        pipeline,
+                        distance,
+                        batch_range: 0..1,
+                        extra_index: PhaseItemExtraIndex::NONE,
+                    }),
+                    Err(err) => error!("{}", err),
+                }
+            }
 
-            opaque_phase.add(
-                Opaque3dBinKey {
-                    draw_function: draw_function_id,
+            if material.key.alpha_blend {
+                transparent_phase.add(TransparentOutline3d {
+                    entity: (render_entity, visible_entity),
+                    draw_function: draw_transparent_outline,
                     pipeline: pipeline_id,
-                    asset_id: mesh_instance.mesh_asset_id.into(),
-                    material_bind_group_id: material.get_bind_group_id().0,
-                    lightmap_image: None,
-                },
-                (render_entity, visible_entity),
-                BinnedRenderPhaseType::BatchableMesh,
-            );
+                    distance,
+                    batch_range: 0..1,
+                    extra_index: PhaseItemExtraIndex::NONE,
+                });
+            } else {
+                opaque_phase.add(
+                    Outline3dBinKey {
+                        pipeline: pipeline_id,
+                        draw_function: draw_opaque_outline,
+                        asset_id: mesh_instance.mesh_asset_id,
+                    },
+                    (render_entity, visible_entity),
+                    // Skinned and morphed meshes opt out of batching, as their
+                    // joints and weights are bound per entity.
+                    BinnedRenderPhaseType::mesh(
+                        mesh_instance
+                            .flags
+                            .contains(RenderMeshInstanceFlags::AUTOMATIC_BATCHING),
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::VertexStepMode;
+
+    use super::*;
+
+    #[test]
+    fn color_target_follows_view_hdr() {
+        let vertex_buffer_layout = VertexBufferLayout {
+            array_stride: 24,
+            step_mode: VertexStepMode::Vertex,
+            attributes: vec![],
+        };
+        let ldr_key = MeshPipelineKey::from_msaa_samples(4);
+        let hdr_key = ldr_key | MeshPipelineKey::from_hdr(true);
+        for (mesh_key, format) in [
+            (ldr_key, TextureFormat::bevy_default()),
+            (hdr_key, ViewTarget::TEXTURE_FORMAT_HDR),
+        ] {
+            for pass in [
+                OutlinePass::Visible,
+                OutlinePass::Occluded,
+                OutlinePass::StencilMask,
+                OutlinePass::StencilHull,
+            ] {
+                let key = OutlinePipelineKey {
+                    mesh_key,
+                    material_key: OutlineMaterialKey::from(&OutlineMaterial::default()),
+                    pass,
+                };
+                let descriptor = outline_pipeline_descriptor(
+                    key,
+                    Handle::default(),
+                    vec![],
+                    vec![],
+                    vertex_buffer_layout.clone(),
+                );
+                let target = descriptor.fragment.unwrap().targets[0].clone().unwrap();
+                assert_eq!(target.format, format);
+            }
         }
     }
 }
@@ -0,0 +1,343 @@
+//! Outlines detected in screen space from the depth and normal prepass textures.
+//!
+//! Unlike the other methods, these also catch creases and intersections between
+//! meshes: a Sobel filter runs over the linear depth and the normals of every
+//! pixel, and the pixels where either changes sharply are drawn as outlines on
+//! top of the view target after the main pass.
+
+use bevy::{
+    core_pipeline::{
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+        prepass::{DepthPrepass, NormalPrepass, ViewPrepassTextures},
+    },
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        extract_component::ExtractComponent,
+        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        render_resource::{
+            BindGroupEntry, BindGroupLayout, BindGroupLayoutEntry, BindingResource, BindingType,
+            BlendState, BufferBindingType, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            DynamicUniformBuffer, FragmentState, LoadOp, MultisampleState, Operations,
+            PipelineCache, PrimitiveState, RenderPassColorAttachment, RenderPassDescriptor,
+            RenderPipelineDescriptor, ShaderStages, ShaderType, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StoreOp, TextureFormat, TextureSampleType,
+            TextureViewDimension,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::{ViewTarget, ViewUniform, ViewUniformOffset, ViewUniforms},
+    },
+};
+
+use crate::window_size::ExtractedViewScaleFactor;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct OutlinePostProcessLabel;
+
+/// Draws outlines wherever the depth or the normal of the scene seen by this
+/// camera changes sharply, including creases and intersections between meshes.
+///
+/// Requires the [`DepthPrepass`] and [`NormalPrepass`] of the camera, which are
+/// added along with it.
+#[derive(Component, Debug, Clone)]
+#[require(DepthPrepass, NormalPrepass)]
+pub struct OutlinePostProcess {
+    /// Thickness of the outlines in logical pixels.
+    pub width: f32,
+    pub color: LinearRgba,
+    /// Smallest change of the view depth, relative to the depth of the pixel,
+    /// detected as an edge.
+    pub depth_threshold: f32,
+    /// Smallest change of the normals detected as an edge, from `0.0` to `2.0`
+    /// for opposite normals.
+    pub normal_threshold: f32,
+}
+
+impl Default for OutlinePostProcess {
+    fn default() -> Self {
+        Self {
+            width: 1.0,
+            color: LinearRgba::BLACK,
+            depth_threshold: 0.1,
+            normal_threshold: 0.5,
+        }
+    }
+}
+
+impl ExtractComponent for OutlinePostProcess {
+    type QueryData = (&'static Self, &'static Camera);
+    type QueryFilter = ();
+    type Out = Self;
+
+    fn extract_component((post_process, camera): QueryItem<'_, Self::QueryData>) -> Option<Self> {
+        camera.is_active.then(|| post_process.clone())
+    }
+}
+
+#[derive(Clone, ShaderType)]
+pub(crate) struct OutlinePostProcessUniform {
+    color: Vec4,
+    width: f32,
+    depth_threshold: f32,
+    normal_threshold: f32,
+}
+
+#[derive(Resource, Default)]
+pub(crate) struct OutlinePostProcessMeta {
+    uniforms: DynamicUniformBuffer<OutlinePostProcessUniform>,
+}
+
+#[derive(Component)]
+pub(crate) struct ViewOutlinePostProcess {
+    pipeline: CachedRenderPipelineId,
+    offset: u32,
+    multisampled: bool,
+}
+
+type PostProcessViewQueryData = (
+    Entity,
+    &'static ViewTarget,
+    &'static Msaa,
+    Option<&'static OutlinePostProcess>,
+    Option<&'static ExtractedViewScaleFactor>,
+);
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_outline_post_process(
+    mut commands: Commands,
+    post_process_pipeline: Res<OutlinePostProcessPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OutlinePostProcessPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    mut meta: ResMut<OutlinePostProcessMeta>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    views: Query<PostProcessViewQueryData>,
+) {
+    meta.uniforms.clear();
+    for (view_entity, view_target, msaa, post_process, scale_factor) in views.iter() {
+        let Some(post_process) = post_process else {
+            commands
+                .entity(view_entity)
+                .remove::<ViewOutlinePostProcess>();
+            continue;
+        };
+        let key = OutlinePostProcessPipelineKey {
+            format: view_target.main_texture_format(),
+            multisampled: msaa.samples() > 1,
+        };
+        let pipeline = pipelines.specialize(&pipeline_cache, &post_process_pipeline, key);
+        let offset = meta.uniforms.push(&OutlinePostProcessUniform {
+            color: post_process.color.to_vec4(),
+            width: post_process.width * scale_factor.map_or(1.0, |scale_factor| scale_factor.0),
+            depth_threshold: post_process.depth_threshold,
+            normal_threshold: post_process.normal_threshold,
+        });
+        commands.entity(view_entity).insert(ViewOutlinePostProcess {
+            pipeline,
+            offset,
+            multisampled: key.multisampled,
+        });
+    }
+    meta.uniforms.write_buffer(&render_device, &render_queue);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct OutlinePostProcessPipelineKey {
+    /// The format of the main texture of the view.
+    format: TextureFormat,
+    /// Whether the prepass textures are multisampled.
+    multisampled: bool,
+}
+
+/// Draws the edges found in the prepass textures onto the view target.
+#[derive(Resource)]
+pub(crate) struct OutlinePostProcessPipeline {
+    /// Layouts for single sampled and multisampled prepass textures.
+    layouts: [BindGroupLayout; 2],
+    shader_handle: Handle<Shader>,
+}
+
+impl FromWorld for OutlinePostProcessPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(
+                Some("outline post process layout"),
+                &[
+                    uniform_layout_entry::<ViewUniform>(0),
+                    uniform_layout_entry::<OutlinePostProcessUniform>(1),
+                    texture_layout_entry(2, TextureSampleType::Depth, multisampled),
+                    texture_layout_entry(
+                        3,
+                        TextureSampleType::Float { filterable: false },
+                        multisampled,
+                    ),
+                ],
+            )
+        });
+        let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
+            include_str!("render/post_process.wgsl"),
+            "render/post_process.wgsl",
+        ));
+        Self {
+            layouts,
+            shader_handle,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for OutlinePostProcessPipeline {
+    type Key = OutlinePostProcessPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut shader_defs = vec![];
+        if key.multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        RenderPipelineDescriptor {
+            label: Some("outline_post_process_pipeline".into()),
+            layout: vec![self.layouts[key.multisampled as usize].clone()],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                shader: self.shader_handle.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: key.format,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            zero_initialize_workgroup_memory: true,
+        }
+    }
+}
+
+fn uniform_layout_entry<T: ShaderType>(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::FRAGMENT,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: true,
+            min_binding_size: Some(T::min_size()),
+        },
+        count: None,
+    }
+}
+
+pub(crate) fn texture_layout_entry(
+    binding: u32,
+    sample_type: TextureSampleType,
+    multisampled: bool,
+) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::FRAGMENT,
+        ty: BindingType::Texture {
+            sample_type,
+            view_dimension: TextureViewDimension::D2,
+            multisampled,
+        },
+        count: None,
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct OutlinePostProcessNode;
+
+impl ViewNode for OutlinePostProcessNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewUniformOffset,
+        &'static ViewPrepassTextures,
+        &'static ViewOutlinePostProcess,
+    );
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, view_target, view_uniform_offset, prepass_textures, post_process): QueryItem<
+            'w,
+            Self::ViewQuery,
+        >,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let post_process_pipeline = world.resource::<OutlinePostProcessPipeline>();
+        let meta = world.resource::<OutlinePostProcessMeta>();
+
+        let (
+            Some(render_pipeline),
+            Some(view_binding),
+            Some(outline_binding),
+            Some(depth_view),
+            Some(normal_view),
+        ) = (
+            pipeline_cache.get_render_pipeline(post_process.pipeline),
+            world.resource::<ViewUniforms>().uniforms.binding(),
+            meta.uniforms.binding(),
+            prepass_textures.depth_view(),
+            prepass_textures.normal_view(),
+        )
+        else {
+            return Ok(());
+        };
+
+        let bind_group = render_context.render_device().create_bind_group(
+            Some("outline post process bind group"),
+            &post_process_pipeline.layouts[post_process.multisampled as usize],
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: view_binding,
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: outline_binding,
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(depth_view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(normal_view),
+                },
+            ],
+        );
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("outline_post_process_pass"),
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: view_target.main_texture_view(),
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        pass.set_render_pipeline(render_pipeline);
+        pass.set_bind_group(
+            0,
+            &bind_group,
+            &[view_uniform_offset.offset, post_process.offset],
+        );
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
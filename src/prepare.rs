@@ -1,22 +1,528 @@
+use std::hash::{Hash, Hasher};
+
 use bevy::{
-    ecs::{change_detection::DetectChanges, query::Changed, world::Ref},
-    prelude::{Assets, Mesh, Query, ResMut},
+    ecs::{change_detection::DetectChanges, world::Ref},
+    log::warn,
+    prelude::{
+        default, AssetEvent, AssetId, Assets, Commands, Component, Deref, Entity, EventReader,
+        Handle, Mesh, Query, RemovedComponents, Res, ResMut, Resource, Without,
+    },
     render::mesh::Mesh3d,
+    utils::{AHasher, HashMap, HashSet},
+};
+
+use crate::{
+    smooth_normal::smooth_normal, OutlineNormalWeighting, OutlineRendered, ATTRIBUTE_OUTLINE_NORMAL,
 };
 
-use crate::{smooth_normal::smooth_normal, OutlineRendered, ATTRIBUTE_OUTLINE_NORMAL};
+/// Where the outline normals computed for meshes without
+/// [`ATTRIBUTE_OUTLINE_NORMAL`] are stored, set from
+/// [`OutlinePlugin::normal_storage`](crate::OutlinePlugin::normal_storage).
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutlineNormalStorage {
+    /// Insert the outline normals into the mesh asset itself. This also changes
+    /// the vertex layout of every entity sharing the mesh, outlined or not.
+    #[default]
+    InPlace,
+    /// Copy the mesh into a derived asset with the outline normals, shared by the
+    /// outlined entities using the same mesh, and point their [`Mesh3d`] at it.
+    /// The original mesh is left untouched and kept in [`OutlineSourceMesh`].
+    DerivedMesh,
+}
+
+/// The distance under which vertices are considered at the same position when
+/// smoothing outline normals, set from
+/// [`OutlinePlugin::weld_tolerance`](crate::OutlinePlugin::weld_tolerance).
+#[derive(Resource, Debug, Clone, Copy, Default)]
+pub struct OutlineWeldTolerance(pub f32);
+
+/// The mesh an outlined entity used before its [`Mesh3d`] was replaced by a mesh
+/// derived with [`OutlineNormalStorage::DerivedMesh`], restored when it is no
+/// longer outlined.
+#[derive(Component, Debug, Clone, Deref)]
+pub struct OutlineSourceMesh(pub Handle<Mesh>);
+
+/// The meshes derived from every source mesh and weighting with [`OutlineNormalStorage::DerivedMesh`].
+#[derive(Resource, Default)]
+pub(crate) struct DerivedOutlineMeshes(
+    HashMap<(AssetId<Mesh>, OutlineNormalWeighting), Handle<Mesh>>,
+);
+
+/// Fingerprints of the geometry of the meshes whose outline normals were computed in
+/// place, telling them apart from meshes with outline normals supplied by the user,
+/// and the weighting they were computed with.
+#[derive(Resource, Default)]
+pub(crate) struct GeneratedOutlineNormals(HashMap<AssetId<Mesh>, (u64, OutlineNormalWeighting)>);
+
+type OutlineMeshQueryData = (
+    Entity,
+    Ref<'static, Mesh3d>,
+    Ref<'static, OutlineRendered>,
+    Option<&'static OutlineSourceMesh>,
+    Option<Ref<'static, OutlineNormalWeighting>>,
+);
 
+/// Computes the outline normals of outlined meshes when they are first outlined, when
+/// the [`Mesh3d`] of an outlined entity changes, and when an outlined mesh is loaded
+/// or modified, e.g. by hot reloading. Outline normals supplied by the user are kept.
+///
+/// With [`OutlineNormalStorage::InPlace`], a mesh only holds one set of outline
+/// normals, so entities sharing it with different [`OutlineNormalWeighting`]s
+/// all use the weighting it was first computed with.
+///
+/// Entities which are no longer outlined get their [`OutlineSourceMesh`] back.
+#[allow(clippy::too_many_arguments)]
 pub fn prepare_outline_mesh(
+    mut commands: Commands,
+    storage: Res<OutlineNormalStorage>,
+    weld_tolerance: Res<OutlineWeldTolerance>,
+    mut derived_meshes: ResMut<DerivedOutlineMeshes>,
+    mut generated: ResMut<GeneratedOutlineNormals>,
     mut meshes: ResMut<Assets<Mesh>>,
-    q_mesh: Query<Ref<Mesh3d>, Changed<OutlineRendered>>,
+    mut mesh_events: EventReader<AssetEvent<Mesh>>,
+    q_mesh: Query<OutlineMeshQueryData>,
+    q_unoutlined: Query<(&Mesh3d, &OutlineSourceMesh), Without<OutlineRendered>>,
+    mut removed_outlines: RemovedComponents<OutlineRendered>,
 ) {
-    for mesh_handle in q_mesh.iter().filter(|mesh| mesh.is_changed()) {
-        let Some(mesh) = meshes.get_mut(mesh_handle.id()) else {
+    for entity in removed_outlines.read() {
+        let Ok((mesh_handle, source)) = q_unoutlined.get(entity) else {
             continue;
         };
-        // Don't have outline normal, just compute it.
-        if !mesh.contains_attribute(ATTRIBUTE_OUTLINE_NORMAL) {
-            mesh.insert_attribute(ATTRIBUTE_OUTLINE_NORMAL, smooth_normal(mesh));
+        let mut entity = commands.entity(entity);
+        entity.remove::<OutlineSourceMesh>();
+        // Keep any mesh set since the entity was outlined.
+        if derived_meshes
+            .0
+            .iter()
+            .any(|(&(id, _), derived)| id == source.id() && *derived == mesh_handle.0)
+        {
+            entity.insert(Mesh3d(source.0.clone()));
+        }
+    }
+
+    let changed_meshes: HashSet<AssetId<Mesh>> = mesh_events
+        .read()
+        .filter_map(|event| match *event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => Some(id),
+            AssetEvent::Removed { .. } | AssetEvent::Unused { .. } => None,
+        })
+        .collect();
+
+    // Forget the meshes which no longer exist.
+    derived_meshes
+        .0
+        .retain(|(source, _), _| meshes.contains(*source));
+    generated.0.retain(|id, _| meshes.contains(*id));
+
+    // The weightings used by the entities outlining every mesh in place.
+    let mut weightings: HashMap<AssetId<Mesh>, HashSet<OutlineNormalWeighting>> = HashMap::new();
+    if *storage == OutlineNormalStorage::InPlace {
+        for (_, mesh_handle, _, _, weighting) in q_mesh.iter() {
+            weightings
+                .entry(mesh_handle.id())
+                .or_default()
+                .insert(weighting.map_or_else(default, |weighting| *weighting));
+        }
+    }
+
+    // Sources whose derived mesh was rebuilt this frame.
+    let mut rebuilt = HashSet::new();
+    for (entity, mesh_handle, rendered, source, weighting) in q_mesh.iter() {
+        let entity_changed = mesh_handle.is_changed()
+            || rendered.is_changed()
+            || weighting.as_ref().is_some_and(DetectChanges::is_changed);
+        let weighting = weighting.map_or_else(default, |weighting| *weighting);
+        match *storage {
+            OutlineNormalStorage::InPlace => {
+                if !entity_changed && !changed_meshes.contains(&mesh_handle.id()) {
+                    continue;
+                }
+                let Some(mesh) = meshes.get(mesh_handle.id()) else {
+                    continue;
+                };
+                let generated_with = generated.0.get(&mesh_handle.id()).copied();
+                // Keep the weighting of the normals while another entity uses it,
+                // rather than recomputing them for each entity in turn.
+                let weighting = match generated_with {
+                    Some((_, generated_weighting))
+                        if generated_weighting != weighting
+                            && weightings[&mesh_handle.id()].contains(&generated_weighting) =>
+                    {
+                        warn!(
+                            "{:?} shares the mesh {:?} with entities using another outline \
+                            normal weighting, keeping {:?}. Use \
+                            `OutlineNormalStorage::DerivedMesh` to weight them separately.",
+                            entity,
+                            mesh_handle.id(),
+                            generated_weighting
+                        );
+                        generated_weighting
+                    }
+                    _ => weighting,
+                };
+                let fingerprint = geometry_fingerprint(mesh, weighting);
+                // Missing outline normals are computed whatever the fingerprint says,
+                // since the mesh may have been replaced by an identical one.
+                let stale = !mesh.contains_attribute(ATTRIBUTE_OUTLINE_NORMAL)
+                    || generated_with.is_some_and(|(generated, _)| generated != fingerprint);
+                if !stale {
+                    continue;
+                }
+                let outline_normals = match smooth_normal(mesh, weld_tolerance.0, weighting) {
+                    Ok(outline_normals) => outline_normals,
+                    Err(err) => {
+                        warn!(
+                            "Skipping outline normals of {:?}: {}",
+                            mesh_handle.id(),
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let Some(mesh) = meshes.get_mut(mesh_handle.id()) else {
+                    continue;
+                };
+                mesh.insert_attribute(ATTRIBUTE_OUTLINE_NORMAL, outline_normals);
+                generated
+                    .0
+                    .insert(mesh_handle.id(), (fingerprint, weighting));
+            }
+            OutlineNormalStorage::DerivedMesh => {
+                // The mesh of the entity is still the one derived from its source.
+                let source = match source {
+                    Some(source)
+                        if derived_meshes.0.get(&(source.id(), weighting))
+                            == Some(&mesh_handle.0) =>
+                    {
+                        source.0.clone()
+                    }
+                    _ => mesh_handle.0.clone(),
+                };
+                let source_changed =
+                    changed_meshes.contains(&source.id()) && !rebuilt.contains(&source.id());
+                let derived = match derived_meshes.0.get(&(source.id(), weighting)).cloned() {
+                    Some(derived) if !source_changed => derived,
+                    derived => {
+                        if derived.is_none() && !entity_changed && !source_changed {
+                            continue;
+                        }
+                        let Some(mesh) = meshes.get(source.id()) else {
+                            continue;
+                        };
+                        // The outline normals are supplied by the user.
+                        if mesh.contains_attribute(ATTRIBUTE_OUTLINE_NORMAL) {
+                            continue;
+                        }
+                        let outline_normals = match smooth_normal(mesh, weld_tolerance.0, weighting)
+                        {
+                            Ok(outline_normals) => outline_normals,
+                            Err(err) => {
+                                warn!("Skipping outline normals of {:?}: {}", source.id(), err);
+                                continue;
+                            }
+                        };
+                        let mut derived_mesh = mesh.clone();
+                        derived_mesh.insert_attribute(ATTRIBUTE_OUTLINE_NORMAL, outline_normals);
+                        rebuilt.insert(source.id());
+                        match derived {
+                            // Keep the handle, so every entity sharing it is updated.
+                            Some(derived) => {
+                                meshes.insert(derived.id(), derived_mesh);
+                                derived
+                            }
+                            None => {
+                                let derived = meshes.add(derived_mesh);
+                                derived_meshes
+                                    .0
+                                    .insert((source.id(), weighting), derived.clone());
+                                derived
+                            }
+                        }
+                    }
+                };
+                if mesh_handle.0 != derived {
+                    commands
+                        .entity(entity)
+                        .insert((Mesh3d(derived), OutlineSourceMesh(source)));
+                }
+            }
+        }
+    }
+}
+
+/// Hashes the positions, normals and indices of a mesh and the weighting of its
+/// normals, which the outline normals depend on.
+fn geometry_fingerprint(mesh: &Mesh, weighting: OutlineNormalWeighting) -> u64 {
+    let mut hasher = AHasher::default();
+    weighting.hash(&mut hasher);
+    for attribute in [Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_NORMAL] {
+        if let Some(values) = mesh.attribute(attribute) {
+            values.get_bytes().hash(&mut hasher);
+        }
+    }
+    if let Some(indices) = mesh.indices() {
+        indices.iter().for_each(|index| index.hash(&mut hasher));
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::AssetPlugin,
+        ecs::event::EventCursor,
+        prelude::*,
+        render::mesh::{PrimitiveTopology, VertexAttributeValues},
+        render::render_asset::RenderAssetUsages,
+    };
+
+    use super::*;
+
+    fn app(storage: OutlineNormalStorage) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<Mesh>()
+            .insert_resource(storage)
+            .init_resource::<OutlineWeldTolerance>()
+            .init_resource::<DerivedOutlineMeshes>()
+            .init_resource::<GeneratedOutlineNormals>()
+            .add_systems(PostUpdate, prepare_outline_mesh);
+        app
+    }
+
+    /// A single triangle facing `+Z`, or `-Z` when flipped.
+    fn triangle(flipped: bool) -> Mesh {
+        let z = if flipped { -1.0 } else { 1.0 };
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, z]; 3])
+    }
+
+    /// Flips the normals of a mesh created by [`triangle`].
+    fn flip(app: &mut App, mesh: AssetId<Mesh>) {
+        let mut meshes = app.world_mut().resource_mut::<Assets<Mesh>>();
+        meshes
+            .get_mut(mesh)
+            .unwrap()
+            .insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0.0, 0.0, -1.0]; 3]);
+    }
+
+    fn outline_normal_z(app: &App, id: AssetId<Mesh>) -> Option<f32> {
+        let mesh = app.world().resource::<Assets<Mesh>>().get(id)?;
+        match mesh.attribute(ATTRIBUTE_OUTLINE_NORMAL)? {
+            VertexAttributeValues::Float32x3(normals) => Some(normals[0][2]),
+            _ => None,
         }
     }
+
+    fn add_mesh(app: &mut App, mesh: Mesh) -> Handle<Mesh> {
+        app.world_mut().resource_mut::<Assets<Mesh>>().add(mesh)
+    }
+
+    #[test]
+    fn computed_when_outlined() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(&mut app, triangle(false));
+        app.world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered));
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(1.0));
+    }
+
+    #[test]
+    fn recomputed_when_mesh_handle_changes() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(&mut app, triangle(false));
+        let entity = app.world_mut().spawn((Mesh3d(mesh), OutlineRendered)).id();
+        app.update();
+
+        let other = add_mesh(&mut app, triangle(true));
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(Mesh3d(other.clone()));
+        app.update();
+        assert_eq!(outline_normal_z(&app, other.id()), Some(-1.0));
+    }
+
+    #[test]
+    fn recomputed_when_mesh_modified() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(&mut app, triangle(false));
+        app.world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered));
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(1.0));
+
+        flip(&mut app, mesh.id());
+        // The modification event is sent at the end of the frame.
+        app.update();
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(-1.0));
+    }
+
+    #[test]
+    fn computed_when_mesh_loaded_later() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = app.world().resource::<Assets<Mesh>>().reserve_handle();
+        app.world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered));
+        app.update();
+
+        app.world_mut()
+            .resource_mut::<Assets<Mesh>>()
+            .insert(mesh.id(), triangle(false));
+        app.update();
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(1.0));
+    }
+
+    #[test]
+    fn recomputed_when_mesh_replaced_by_identical_mesh() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(&mut app, triangle(false));
+        app.world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered));
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(1.0));
+
+        // As when a glTF is hot reloaded without changes.
+        app.world_mut()
+            .resource_mut::<Assets<Mesh>>()
+            .insert(mesh.id(), triangle(false));
+        assert_eq!(outline_normal_z(&app, mesh.id()), None);
+        app.update();
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(1.0));
+    }
+
+    #[test]
+    fn unsupported_meshes_are_skipped() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let without_normals = add_mesh(&mut app, {
+            let mut mesh = triangle(false);
+            mesh.remove_attribute(Mesh::ATTRIBUTE_NORMAL);
+            mesh
+        });
+        let unsupported = add_mesh(
+            &mut app,
+            Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::default())
+                .with_inserted_attribute(
+                    Mesh::ATTRIBUTE_POSITION,
+                    vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]],
+                ),
+        );
+        app.world_mut().spawn_batch([
+            (Mesh3d(unsupported.clone()), OutlineRendered),
+            (Mesh3d(without_normals.clone()), OutlineRendered),
+        ]);
+        app.update();
+        assert_eq!(outline_normal_z(&app, unsupported.id()), None);
+        // Computed from the winding of the triangle.
+        assert_eq!(outline_normal_z(&app, without_normals.id()), Some(1.0));
+    }
+
+    #[test]
+    fn user_supplied_normals_are_kept() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(
+            &mut app,
+            triangle(false)
+                .with_inserted_attribute(ATTRIBUTE_OUTLINE_NORMAL, vec![[0.0, 0.0, 0.5]; 3]),
+        );
+        app.world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered));
+        app.update();
+
+        flip(&mut app, mesh.id());
+        app.update();
+        app.update();
+        assert_eq!(outline_normal_z(&app, mesh.id()), Some(0.5));
+    }
+
+    #[test]
+    fn shared_mesh_keeps_first_weighting() {
+        let mut app = app(OutlineNormalStorage::InPlace);
+        let mesh = add_mesh(&mut app, triangle(false));
+        app.world_mut().spawn_batch([
+            (
+                Mesh3d(mesh.clone()),
+                OutlineRendered,
+                OutlineNormalWeighting::Uniform,
+            ),
+            (
+                Mesh3d(mesh.clone()),
+                OutlineRendered,
+                OutlineNormalWeighting::Angle,
+            ),
+        ]);
+        app.update();
+        app.update();
+
+        let mut events = EventCursor::<AssetEvent<Mesh>>::default();
+        let mut modified = |app: &App| {
+            events
+                .read(app.world().resource::<Events<AssetEvent<Mesh>>>())
+                .filter(|event| event.is_modified(mesh.id()))
+                .count()
+        };
+        modified(&app);
+        for _ in 0..3 {
+            app.update();
+            assert_eq!(modified(&app), 0);
+        }
+    }
+
+    #[test]
+    fn derived_mesh_follows_source() {
+        let mut app = app(OutlineNormalStorage::DerivedMesh);
+        let mesh = add_mesh(&mut app, triangle(false));
+        let entity = app
+            .world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered))
+            .id();
+        app.update();
+
+        let derived = app.world().get::<Mesh3d>(entity).unwrap().id();
+        assert_ne!(derived, mesh.id());
+        assert_eq!(outline_normal_z(&app, mesh.id()), None);
+        assert_eq!(outline_normal_z(&app, derived), Some(1.0));
+        assert_eq!(
+            app.world().get::<OutlineSourceMesh>(entity).unwrap().id(),
+            mesh.id()
+        );
+
+        flip(&mut app, mesh.id());
+        app.update();
+        app.update();
+        assert_eq!(app.world().get::<Mesh3d>(entity).unwrap().id(), derived);
+        assert_eq!(outline_normal_z(&app, mesh.id()), None);
+        assert_eq!(outline_normal_z(&app, derived), Some(-1.0));
+    }
+
+    #[test]
+    fn source_mesh_restored_when_no_longer_outlined() {
+        let mut app = app(OutlineNormalStorage::DerivedMesh);
+        let mesh = add_mesh(&mut app, triangle(false));
+        let entity = app
+            .world_mut()
+            .spawn((Mesh3d(mesh.clone()), OutlineRendered))
+            .id();
+        app.update();
+        assert_ne!(app.world().get::<Mesh3d>(entity).unwrap().id(), mesh.id());
+
+        app.world_mut()
+            .entity_mut(entity)
+            .remove::<OutlineRendered>();
+        app.update();
+        assert_eq!(app.world().get::<Mesh3d>(entity).unwrap().id(), mesh.id());
+        assert!(app.world().get::<OutlineSourceMesh>(entity).is_none());
+    }
 }
@@ -0,0 +1,36 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+struct JumpFloodStep {
+    step: f32,
+};
+
+@group(0) @binding(0) var seeds: texture_2d<f32>;
+@group(0) @binding(1) var<uniform> jump_flood: JumpFloodStep;
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(seeds));
+    let pixel = vec2<i32>(in.position.xy);
+    let step = i32(jump_flood.step);
+
+    var nearest = vec4<f32>(0.0);
+    var nearest_distance = 3.4e38;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let coord = pixel + vec2<i32>(x, y) * step;
+            if any(coord < vec2<i32>(0)) || any(coord >= size) {
+                continue;
+            }
+            let seed = textureLoad(seeds, coord, 0);
+            if seed.w == 0.0 {
+                continue;
+            }
+            let distance = length(in.position.xy - seed.xy);
+            if distance < nearest_distance {
+                nearest = seed;
+                nearest_distance = distance;
+            }
+        }
+    }
+    return nearest;
+}
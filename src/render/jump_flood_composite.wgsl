@@ -0,0 +1,24 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+@group(0) @binding(0) var seeds: texture_2d<f32>;
+@group(0) @binding(1) var colors: texture_2d<f32>;
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let seed = textureLoad(seeds, vec2<i32>(in.position.xy), 0);
+    // No outlined mesh nearby.
+    if seed.w == 0.0 {
+        discard;
+    }
+    let distance = length(in.position.xy - seed.xy);
+    // Inside the silhouette of the outlined mesh.
+    if distance < 0.5 {
+        discard;
+    }
+    let coverage = saturate(seed.z - distance + 0.5);
+    if coverage == 0.0 {
+        discard;
+    }
+    let color = textureLoad(colors, vec2<i32>(seed.xy), 0);
+    return vec4<f32>(color.rgb, color.a * coverage);
+}
@@ -0,0 +1,40 @@
+#import bevy_render::view::View
+
+@group(0) @binding(0) var<uniform> view: View;
+
+struct JumpFloodOutline {
+    world_from_local: mat4x4<f32>,
+    color: vec4<f32>,
+    width: f32,
+};
+
+@group(1) @binding(0) var<uniform> outline: JumpFloodOutline;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // xy: position of the seed in pixels, z: outline width, w: 1.0 if this is a seed.
+    @location(1) seed: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = view.clip_from_world * outline.world_from_local * vec4<f32>(vertex.position, 1.0);
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.color = outline.color;
+    out.seed = vec4<f32>(in.clip_position.xy, outline.width, 1.0);
+    return out;
+}
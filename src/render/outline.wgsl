@@ -1,42 +1,144 @@
 #import bevy_pbr::{
-    mesh_bindings::mesh,
-    mesh_functions::get_world_from_local,
     mesh_view_bindings::view,
+    morph,
+    skinning,
 }
 
 struct Vertex {
     @builtin(instance_index) instance_index: u32,
+#ifdef MORPH_TARGETS
+    @builtin(vertex_index) index: u32,
+#endif
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
+#ifdef SKINNED
+    @location(2) joint_indices: vec4<u32>,
+    @location(3) joint_weights: vec4<f32>,
+#endif
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
 };
 
-@group(2) @binding(0) var<uniform> outline_width: f32;
-@group(2) @binding(1) var<uniform> outline_color: vec4<f32>;
+struct OutlineInstance {
+    world_from_local: mat4x4<f32>,
+    normal_from_local: mat3x3<f32>,
+    color: vec4<f32>,
+    occluded_color: vec4<f32>,
+    // x: near, y: far, z: far width scale, w: far alpha.
+    falloff: vec4<f32>,
+    // x: min width, y: max width.
+    width_range: vec2<f32>,
+    width: f32,
+    first_vertex_index: u32,
+};
 
+#ifdef PER_OBJECT_BUFFER_BATCH_SIZE
+@group(2) @binding(0) var<uniform> outline_instances: array<OutlineInstance, #{PER_OBJECT_BUFFER_BATCH_SIZE}u>;
+#else
+@group(2) @binding(0) var<storage> outline_instances: array<OutlineInstance>;
+#endif
 
 struct DoubleReciprocalWindowSize {
     size: vec2<f32>,
+    scale_factor: f32,
 };
 
 @group(3) @binding(0)
 var<uniform> window_size: DoubleReciprocalWindowSize;
 
+// Interpolation factor of the falloff, from 0.0 at `near` to 1.0 at `far`.
+fn falloff_factor(falloff: vec4<f32>, world_origin: vec3<f32>) -> f32 {
+    let near = falloff.x;
+    let far = falloff.y;
+    if far <= near {
+        return 0.0;
+    }
+    let distance = length(view.world_position - world_origin);
+    return saturate((distance - near) / (far - near));
+}
+
+#ifdef MORPH_TARGETS
+// Applies the weighted morph targets to the position and the outline normal.
+//
+// The outline normal is displaced like the normal of the vertex, which it
+// matches on smooth surfaces such as faces.
+fn morph_vertex(vertex_in: Vertex) -> Vertex {
+    var vertex = vertex_in;
+    // The vertices of several meshes share a vertex buffer.
+    let vertex_index = vertex.index - outline_instances[vertex.instance_index].first_vertex_index;
+    let weight_count = morph::layer_count();
+    for (var i: u32 = 0u; i < weight_count; i++) {
+        let weight = morph::weight_at(i);
+        if weight == 0.0 {
+            continue;
+        }
+        vertex.position += weight * morph::morph(vertex_index, morph::position_offset, i);
+        vertex.normal += weight * morph::morph(vertex_index, morph::normal_offset, i);
+    }
+    vertex.normal = normalize(vertex.normal);
+    return vertex;
+}
+#endif
+
 @vertex
-fn vertex(vertex: Vertex) -> VertexOutput {
-    let mvp = view.clip_from_world * get_world_from_local(vertex.instance_index);
+fn vertex(vertex_no_morph: Vertex) -> VertexOutput {
+#ifdef MORPH_TARGETS
+    let vertex = morph_vertex(vertex_no_morph);
+#else
+    let vertex = vertex_no_morph;
+#endif
+    let instance = outline_instances[vertex.instance_index];
+    // The falloff is measured from the origin of the entity, even for skinned meshes.
+    let falloff = falloff_factor(instance.falloff, instance.world_from_local[3].xyz);
+#ifdef SKINNED
+    let world_from_local = skinning::skin_model(vertex.joint_indices, vertex.joint_weights);
+#else
+    let world_from_local = instance.world_from_local;
+#endif
+#ifdef OUTLINE_STENCIL_MASK
+    // The silhouette of the mesh itself.
+    let width = 0.0;
+#else
+    let width = clamp(
+        instance.width * mix(1.0, instance.falloff.z, falloff),
+        instance.width_range.x,
+        instance.width_range.y,
+    );
+#endif
+    var out: VertexOutput;
+#ifdef OUTLINE_OCCLUDED
+    let color = instance.occluded_color;
+#else
+    let color = instance.color;
+#endif
+    out.color = vec4<f32>(color.rgb, color.a * mix(1.0, instance.falloff.w, falloff));
+#ifdef OUTLINE_WIDTH_WORLD
+    let world_position = world_from_local * vec4<f32>(vertex.position, 1.0);
+#ifdef SKINNED
+    let world_normal = skinning::skin_normals(world_from_local, vertex.normal);
+#else
+    let world_normal = normalize(instance.normal_from_local * vertex.normal);
+#endif
+    out.clip_position = view.clip_from_world * vec4<f32>(world_position.xyz + world_normal * width, 1.0);
+#else
+#ifdef OUTLINE_WIDTH_LOGICAL_PIXELS
+    let pixel_width = width * window_size.scale_factor;
+#else
+    let pixel_width = width;
+#endif
+    let mvp = view.clip_from_world * world_from_local;
     let clip_position = mvp * vec4<f32>(vertex.position, 1.0);
     let clip_normal = mvp * vec4<f32>(vertex.normal, 0.0);
-    let extrude_offset = normalize(clip_normal.xy) * outline_width * clip_position.w * window_size.size;
-    var out: VertexOutput;
+    let extrude_offset = normalize(clip_normal.xy) * pixel_width * clip_position.w * window_size.size;
     out.clip_position = vec4<f32>(clip_position.xy + extrude_offset, clip_position.zw);
+#endif
     return out;
 }
 
 @fragment
-fn fragment() -> @location(0) vec4<f32> {
-    return outline_color;
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
 }
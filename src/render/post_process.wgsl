@@ -0,0 +1,74 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_render::view::View
+
+struct OutlinePostProcess {
+    color: vec4<f32>,
+    width: f32,
+    depth_threshold: f32,
+    normal_threshold: f32,
+};
+
+@group(0) @binding(0) var<uniform> view: View;
+@group(0) @binding(1) var<uniform> outline: OutlinePostProcess;
+#ifdef MULTISAMPLED
+@group(0) @binding(2) var depth_texture: texture_depth_multisampled_2d;
+@group(0) @binding(3) var normal_texture: texture_multisampled_2d<f32>;
+#else
+@group(0) @binding(2) var depth_texture: texture_depth_2d;
+@group(0) @binding(3) var normal_texture: texture_2d<f32>;
+#endif
+
+// The 8 neighbours of a pixel and their Sobel kernel weights, starting from the
+// top left one.
+const OFFSETS = array<vec2<f32>, 8>(
+    vec2(-1.0, -1.0), vec2(0.0, -1.0), vec2(1.0, -1.0), vec2(-1.0, 0.0),
+    vec2(1.0, 0.0), vec2(-1.0, 1.0), vec2(0.0, 1.0), vec2(1.0, 1.0),
+);
+const WEIGHTS_X = array<f32, 8>(-1.0, 0.0, 1.0, -2.0, 2.0, -1.0, 0.0, 1.0);
+const WEIGHTS_Y = array<f32, 8>(-1.0, -2.0, -1.0, 0.0, 0.0, 1.0, 2.0, 1.0);
+
+fn texel(position: vec2<f32>) -> vec2<i32> {
+    let size = vec2<i32>(textureDimensions(depth_texture)) - 1;
+    return clamp(vec2<i32>(round(position)), vec2(0), size);
+}
+
+// Distance from the camera plane, with the far plane of the reversed depth
+// buffer mapped to a very distant point instead of infinity.
+fn linear_depth(position: vec2<f32>) -> f32 {
+    let depth = max(textureLoad(depth_texture, texel(position), 0), 1e-6);
+    let view_position = view.view_from_clip * vec4(0.0, 0.0, depth, 1.0);
+    return -view_position.z / view_position.w;
+}
+
+fn prepass_normal(position: vec2<f32>) -> vec3<f32> {
+    return textureLoad(normal_texture, texel(position), 0).xyz * 2.0 - 1.0;
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    // Edges are found up to `spacing` pixels away on both sides, so the lines
+    // are about `width` pixels wide.
+    let spacing = max(outline.width * 0.5, 1.0);
+
+    var depth_x = 0.0;
+    var depth_y = 0.0;
+    var normal_x = vec3(0.0);
+    var normal_y = vec3(0.0);
+    for (var i = 0; i < 8; i += 1) {
+        let position = in.position.xy + OFFSETS[i] * spacing;
+        let depth = linear_depth(position);
+        let normal = prepass_normal(position);
+        depth_x += WEIGHTS_X[i] * depth;
+        depth_y += WEIGHTS_Y[i] * depth;
+        normal_x += WEIGHTS_X[i] * normal;
+        normal_y += WEIGHTS_Y[i] * normal;
+    }
+
+    // Both kernels weigh 4 on each side.
+    let depth_edge = length(vec2(depth_x, depth_y)) * 0.25 / linear_depth(in.position.xy);
+    let normal_edge = sqrt(dot(normal_x, normal_x) + dot(normal_y, normal_y)) * 0.25;
+    if depth_edge < outline.depth_threshold && normal_edge < outline.normal_threshold {
+        discard;
+    }
+    return outline.color;
+}
@@ -0,0 +1,23 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+#ifdef MULTISAMPLED
+@group(0) @binding(0) var depth_texture: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0) var depth_texture: texture_depth_2d;
+#endif
+
+// Copies the depth of the scene into the depth-stencil texture of the stencil
+// outlines, so they are occluded like the other outlines.
+@fragment
+fn fragment(
+    in: FullscreenVertexOutput,
+#ifdef MULTISAMPLED
+    @builtin(sample_index) sample_index: u32,
+#endif
+) -> @builtin(frag_depth) f32 {
+#ifdef MULTISAMPLED
+    return textureLoad(depth_texture, vec2<i32>(in.position.xy), i32(sample_index));
+#else
+    return textureLoad(depth_texture, vec2<i32>(in.position.xy), 0);
+#endif
+}
@@ -1,9 +1,16 @@
-use std::hash::Hash;
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    hash::Hash,
+};
 
 use bevy::{
-    math::{FloatOrd, Vec3A},
-    prelude::{Deref, DerefMut},
-    render::mesh::{Mesh, VertexAttributeValues},
+    math::{FloatOrd, IVec3, Vec3A},
+    prelude::{Component, Deref, DerefMut},
+    render::{
+        mesh::{Mesh, PrimitiveTopology, VertexAttributeValues},
+        render_resource::VertexFormat,
+    },
     utils::HashMap,
 };
 
@@ -29,35 +36,353 @@ impl Hash for Float3Ord {
     }
 }
 
-/// smooth the normals of vertex at same position
-pub(crate) fn smooth_normal(mesh: &Mesh) -> VertexAttributeValues {
-    let mut normals_map = HashMap::new();
-    let v_positions = get_float3x3(mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap());
-    let v_normals = get_float3x3(mesh.attribute(Mesh::ATTRIBUTE_NORMAL).unwrap());
-    let mut smoothed_normals = vec![[0.; 3]; v_positions.len()];
-    v_positions
+/// Why the outline normals of a mesh cannot be computed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SmoothNormalError {
+    /// The mesh has no [`Mesh::ATTRIBUTE_POSITION`].
+    MissingPositions,
+    /// The positions are not [`VertexFormat::Float32x3`].
+    UnsupportedPositionFormat(VertexFormat),
+    /// The normals are not [`VertexFormat::Float32x3`].
+    UnsupportedNormalFormat(VertexFormat),
+    /// The mesh has no normals, and they can only be computed for triangle lists.
+    UnsupportedTopology(PrimitiveTopology),
+    /// The mesh has a different number of normals and positions.
+    NormalCountMismatch { positions: usize, normals: usize },
+    /// An index refers to a vertex past the end of the mesh.
+    IndexOutOfBounds { index: usize, vertices: usize },
+}
+
+impl Display for SmoothNormalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingPositions => write!(f, "the mesh has no vertex positions"),
+            Self::UnsupportedPositionFormat(format) => {
+                write!(f, "vertex positions must be Float32x3, not {:?}", format)
+            }
+            Self::UnsupportedNormalFormat(format) => {
+                write!(f, "vertex normals must be Float32x3, not {:?}", format)
+            }
+            Self::UnsupportedTopology(topology) => write!(
+                f,
+                "normals can only be computed for triangle lists, not {:?}",
+                topology
+            ),
+            Self::NormalCountMismatch { positions, normals } => write!(
+                f,
+                "the mesh has {} vertex positions but {} normals",
+                positions, normals
+            ),
+            Self::IndexOutOfBounds { index, vertices } => write!(
+                f,
+                "index {} is out of bounds of the {} vertices",
+                index, vertices
+            ),
+        }
+    }
+}
+
+impl Error for SmoothNormalError {}
+
+/// How the normals of the vertices at the same position are averaged into their
+/// outline normal. Add it to an outlined entity to choose the weighting of its mesh.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum OutlineNormalWeighting {
+    /// Average the normals of the vertices, so a face split into many vertices
+    /// weighs more. Uses the area of the triangles when the mesh has no normals.
+    #[default]
+    Uniform,
+    /// Average the normals of the triangles around the vertices, weighted by the
+    /// area of the triangles.
+    Area,
+    /// Average the normals of the triangles around the vertices, weighted by the
+    /// angle of the triangles at the vertices, so the outline is evenly thick around
+    /// corners whatever the triangulation of their faces.
+    Angle,
+}
+
+/// smooth the normals of vertex at same position, or closer than `weld_tolerance`
+pub(crate) fn smooth_normal(
+    mesh: &Mesh,
+    weld_tolerance: f32,
+    weighting: OutlineNormalWeighting,
+) -> Result<VertexAttributeValues, SmoothNormalError> {
+    let v_positions = get_float3x3(
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+            .ok_or(SmoothNormalError::MissingPositions)?,
+    )
+    .map_err(SmoothNormalError::UnsupportedPositionFormat)?;
+    let v_normals = match (weighting, mesh.attribute(Mesh::ATTRIBUTE_NORMAL)) {
+        (OutlineNormalWeighting::Uniform, Some(normals)) => {
+            let normals =
+                get_float3x3(normals).map_err(SmoothNormalError::UnsupportedNormalFormat)?;
+            if normals.len() != v_positions.len() {
+                return Err(SmoothNormalError::NormalCountMismatch {
+                    positions: v_positions.len(),
+                    normals: normals.len(),
+                });
+            }
+            normals.iter().map(|&normal| normal.into()).collect()
+        }
+        (OutlineNormalWeighting::Uniform, None) => triangle_normals(
+            v_positions,
+            &triangles(mesh, v_positions.len())?,
+            OutlineNormalWeighting::Area,
+        ),
+        (weighting, _) => {
+            triangle_normals(v_positions, &triangles(mesh, v_positions.len())?, weighting)
+        }
+    };
+    let groups = weld(v_positions, weld_tolerance);
+    let mut group_normals = vec![Vec3A::ZERO; v_positions.len()];
+    for (&group, normal) in groups.iter().zip(v_normals) {
+        group_normals[group] += normal;
+    }
+    let smoothed_normals = groups
         .iter()
-        .zip(v_normals.iter())
-        .enumerate()
-        .for_each(|(index, (pos, normal))| {
-            let key = Float3Ord(*pos);
-            let entry = normals_map.entry(key).or_insert((vec![], Vec3A::ZERO));
-            entry.0.push(index);
-            entry.1 += Vec3A::from(*normal);
-        });
-    normals_map.drain().for_each(|(_, (indices, normal))| {
-        let ave_normal = normal.normalize().into();
-        indices.into_iter().for_each(|index| {
-            smoothed_normals[index] = ave_normal;
-        })
-    });
-    VertexAttributeValues::Float32x3(smoothed_normals)
+        .map(|&group| group_normals[group].normalize_or_zero().into())
+        .collect();
+    Ok(VertexAttributeValues::Float32x3(smoothed_normals))
+}
+
+/// Assigns every vertex to a group of coincident vertices, identified by the index of
+/// its first vertex.
+///
+/// With a positive `tolerance`, a vertex joins the group of the first vertex found
+/// within that distance, looked up in a grid of cells of the size of the tolerance.
+/// Otherwise, only vertices at the exact same position are grouped.
+fn weld(positions: &[[f32; 3]], tolerance: f32) -> Vec<usize> {
+    if tolerance <= 0.0 {
+        let mut groups = HashMap::new();
+        return positions
+            .iter()
+            .enumerate()
+            .map(|(index, position)| *groups.entry(Float3Ord(*position)).or_insert(index))
+            .collect();
+    }
+
+    let cell_of = |position: Vec3A| (position / tolerance).floor().as_ivec3();
+    let mut cells: HashMap<IVec3, Vec<usize>> = HashMap::new();
+    let mut groups = Vec::with_capacity(positions.len());
+    for (index, position) in positions.iter().enumerate() {
+        let position = Vec3A::from(*position);
+        let cell = cell_of(position);
+        // A vertex within the tolerance is at most one cell away.
+        let group = NEIGHBOUR_CELLS
+            .iter()
+            .filter_map(|offset| cells.get(&(cell + *offset)))
+            .flatten()
+            .copied()
+            .find(|&first| position.distance(Vec3A::from(positions[first])) <= tolerance);
+        groups.push(group.unwrap_or_else(|| {
+            cells.entry(cell).or_default().push(index);
+            index
+        }));
+    }
+    groups
+}
+
+/// The offsets of a cell and of its 26 neighbours.
+const NEIGHBOUR_CELLS: [IVec3; 27] = {
+    let mut offsets = [IVec3::ZERO; 27];
+    let mut i = 0;
+    while i < 27 {
+        offsets[i] = IVec3::new(i as i32 % 3 - 1, i as i32 / 3 % 3 - 1, i as i32 / 9 - 1);
+        i += 1;
+    }
+    offsets
+};
+
+/// The indices of the vertices of every triangle of a triangle list.
+fn triangles(mesh: &Mesh, vertices: usize) -> Result<Vec<[usize; 3]>, SmoothNormalError> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        return Err(SmoothNormalError::UnsupportedTopology(
+            mesh.primitive_topology(),
+        ));
+    }
+    let indices: Vec<usize> = match mesh.indices() {
+        Some(indices) => indices.iter().collect(),
+        None => (0..vertices).collect(),
+    };
+    if let Some(&index) = indices.iter().find(|&&index| index >= vertices) {
+        return Err(SmoothNormalError::IndexOutOfBounds { index, vertices });
+    }
+    Ok(indices
+        .chunks_exact(3)
+        .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+        .collect())
+}
+
+/// Sums the normals of the triangles around every vertex, weighted by their area or
+/// by their angle at the vertex.
+fn triangle_normals(
+    positions: &[[f32; 3]],
+    triangles: &[[usize; 3]],
+    weighting: OutlineNormalWeighting,
+) -> Vec<Vec3A> {
+    let mut normals = vec![Vec3A::ZERO; positions.len()];
+    for triangle in triangles {
+        let corners = triangle.map(|index| Vec3A::from(positions[index]));
+        // Its length is twice the area of the triangle.
+        let normal = (corners[1] - corners[0]).cross(corners[2] - corners[0]);
+        if normal == Vec3A::ZERO {
+            continue;
+        }
+        for (corner, &index) in triangle.iter().enumerate() {
+            normals[index] += match weighting {
+                OutlineNormalWeighting::Uniform | OutlineNormalWeighting::Area => normal,
+                OutlineNormalWeighting::Angle => {
+                    let position = corners[corner];
+                    let angle = (corners[(corner + 1) % 3] - position)
+                        .angle_between(corners[(corner + 2) % 3] - position);
+                    normal.normalize() * angle
+                }
+            };
+        }
+    }
+    normals
 }
 
 #[inline(always)]
-fn get_float3x3(values: &VertexAttributeValues) -> &Vec<[f32; 3]> {
+fn get_float3x3(values: &VertexAttributeValues) -> Result<&Vec<[f32; 3]>, VertexFormat> {
     match values {
-        VertexAttributeValues::Float32x3(v) => v,
-        _ => panic!("Vertex Position must be a Float32x3"),
+        VertexAttributeValues::Float32x3(v) => Ok(v),
+        other => Err(other.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{math::Vec3, prelude::Cuboid};
+
+    use super::*;
+
+    /// A cube whose faces don't share vertices, with every vertex moved by up to
+    /// `crack` so that the vertices of a corner are only nearly coincident.
+    fn cracked_cube(crack: f32) -> Mesh {
+        let mut mesh = Mesh::from(Cuboid::default());
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        else {
+            unreachable!();
+        };
+        let count = positions.len() as f32;
+        for (index, position) in positions.iter_mut().enumerate() {
+            position[0] += crack * index as f32 / count;
+            position[2] -= crack * index as f32 / count;
+        }
+        mesh
+    }
+
+    fn normals(values: VertexAttributeValues) -> Vec<Vec3> {
+        match values {
+            VertexAttributeValues::Float32x3(normals) => {
+                normals.into_iter().map(Vec3::from).collect()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn cracked_cube_splits_without_tolerance() {
+        let normals = normals(
+            smooth_normal(&cracked_cube(1e-4), 0.0, OutlineNormalWeighting::Uniform).unwrap(),
+        );
+        // Every vertex keeps the normal of its face.
+        for normal in normals {
+            assert!(normal.abs().max_element() > 0.999, "{normal}");
+        }
+    }
+
+    #[test]
+    fn cracked_cube_welds_within_tolerance() {
+        let normals = normals(
+            smooth_normal(&cracked_cube(1e-4), 1e-3, OutlineNormalWeighting::Uniform).unwrap(),
+        );
+        // Every vertex points away from the center along the diagonal of its corner.
+        for normal in normals {
+            assert!(
+                normal
+                    .abs()
+                    .abs_diff_eq(Vec3::splat(1.0 / 3f32.sqrt()), 1e-5),
+                "{normal}"
+            );
+        }
+    }
+
+    #[test]
+    fn cracks_wider_than_tolerance_are_kept() {
+        let normals = normals(
+            smooth_normal(&cracked_cube(1e-2), 1e-4, OutlineNormalWeighting::Uniform).unwrap(),
+        );
+        assert!(normals
+            .iter()
+            .any(|normal| normal.abs().max_element() > 0.999));
+    }
+
+    #[test]
+    fn welds_across_cell_boundaries() {
+        // Both vertices of each pair lie in different cells of the tolerance grid.
+        let mesh = Mesh::new(PrimitiveTopology::TriangleList, Default::default())
+            .with_inserted_attribute(
+                Mesh::ATTRIBUTE_POSITION,
+                vec![
+                    [0.99999, 0.0, 0.0],
+                    [1.00001, 0.0, 0.0],
+                    [-0.00001, 0.0, 0.0],
+                    [0.00001, 0.0, 0.0],
+                ],
+            )
+            .with_inserted_attribute(
+                Mesh::ATTRIBUTE_NORMAL,
+                vec![
+                    [1.0, 0.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                    [1.0, 0.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                ],
+            );
+        let expected = Vec3::new(1.0, 1.0, 0.0).normalize();
+        for normal in normals(smooth_normal(&mesh, 1e-3, OutlineNormalWeighting::Uniform).unwrap())
+        {
+            assert!(normal.abs_diff_eq(expected, 1e-6), "{normal}");
+        }
+    }
+
+    /// The corner of a cube at the origin, whose `Z` face is split into two triangles.
+    fn split_corner() -> Mesh {
+        let triangles = [
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]],
+            [[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        ];
+        let normals = [
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 0.0, 0.0],
+        ];
+        Mesh::new(PrimitiveTopology::TriangleList, Default::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, triangles.concat())
+            .with_inserted_attribute(
+                Mesh::ATTRIBUTE_NORMAL,
+                normals
+                    .iter()
+                    .flat_map(|&normal| [normal; 3])
+                    .collect::<Vec<_>>(),
+            )
+    }
+
+    #[test]
+    fn angle_weighting_ignores_triangulation() {
+        let corner =
+            |weighting| normals(smooth_normal(&split_corner(), 0.0, weighting).unwrap())[0];
+        let diagonal = Vec3::ONE.normalize();
+        assert!(corner(OutlineNormalWeighting::Angle).abs_diff_eq(diagonal, 1e-6));
+        // The split face weighs twice as much.
+        let biased = Vec3::new(1.0, 1.0, 2.0).normalize();
+        assert!(corner(OutlineNormalWeighting::Uniform).abs_diff_eq(biased, 1e-6));
+        assert!(corner(OutlineNormalWeighting::Area).abs_diff_eq(biased, 1e-6));
     }
 }
@@ -0,0 +1,378 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::OutlineMaterial;
+
+/// A layer of an [`OutlineStack`]. Layers later in this list take priority,
+/// and custom layers take priority over the built-in ones in increasing order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Reflect)]
+pub enum OutlineLayer {
+    /// The outline set by the game, e.g. to highlight a quest target.
+    Base,
+    /// The outline of a selected entity.
+    Selected,
+    /// The outline of an entity under the pointer.
+    Hovered,
+    /// The outline of an entity being pressed.
+    Pressed,
+    /// A layer of the game on top of the built-in ones.
+    Custom(u8),
+}
+
+/// Outlines of an entity layered by priority, of which only the top one is
+/// drawn. Removing a layer restores the outline of the layer below it, and the
+/// outline is removed with the last layer.
+///
+/// Picking interactions push their layers here, so they temporarily override
+/// and then restore any outline the game has set, including an
+/// [`Outline`](crate::Outline) component, which is only drawn again once the
+/// layers above [`OutlineLayer::Base`] are removed. A
+/// [`MeshMaterial3d<OutlineMaterial>`] set or removed by the game on an entity
+/// with a stack sets or removes its [`OutlineLayer::Base`].
+#[derive(Component, Clone, Debug, Default)]
+pub struct OutlineStack {
+    layers: HashMap<OutlineLayer, Handle<OutlineMaterial>>,
+    /// The outline last drawn by the stack, telling it apart from the outlines
+    /// set by the game.
+    drawn: Option<Handle<OutlineMaterial>>,
+}
+
+impl OutlineStack {
+    /// Sets the outline of `layer`, returning the outline it replaces.
+    pub fn insert(
+        &mut self,
+        layer: OutlineLayer,
+        outline: Handle<OutlineMaterial>,
+    ) -> Option<Handle<OutlineMaterial>> {
+        self.layers.insert(layer, outline)
+    }
+
+    /// Removes the outline of `layer`, returning it.
+    pub fn remove(&mut self, layer: OutlineLayer) -> Option<Handle<OutlineMaterial>> {
+        self.layers.remove(&layer)
+    }
+
+    pub fn get(&self, layer: OutlineLayer) -> Option<&Handle<OutlineMaterial>> {
+        self.layers.get(&layer)
+    }
+
+    /// The layer drawn and its outline.
+    pub fn top(&self) -> Option<(OutlineLayer, &Handle<OutlineMaterial>)> {
+        self.layers
+            .iter()
+            .max_by_key(|(layer, _)| **layer)
+            .map(|(layer, outline)| (*layer, outline))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Whether a layer above [`OutlineLayer::Base`] is drawn, in place of the
+    /// [`Outline`](crate::Outline) of the entity if it has one.
+    pub fn overrides_base(&self) -> bool {
+        self.top()
+            .is_some_and(|(layer, _)| layer != OutlineLayer::Base)
+    }
+}
+
+/// Sets or removes the outline of a layer of `entity`, creating its
+/// [`OutlineStack`] if needed with the outline it already has as
+/// [`OutlineLayer::Base`]. Removing a layer never creates a stack.
+#[cfg_attr(not(feature = "picking"), allow(dead_code))]
+pub(crate) fn set_outline_layer(
+    commands: &mut Commands,
+    entity: Entity,
+    layer: OutlineLayer,
+    outline: Option<Handle<OutlineMaterial>>,
+) {
+    let Some(mut entity_commands) = commands.get_entity(entity) else {
+        return;
+    };
+    entity_commands.queue(move |entity: Entity, world: &mut World| {
+        // The entity may be despawned by the time the command is applied, e.g.
+        // when a layer is removed along with it.
+        let Ok(mut entity) = world.get_entity_mut(entity) else {
+            return;
+        };
+        if !entity.contains::<OutlineStack>() {
+            if outline.is_none() {
+                return;
+            }
+            let mut stack = OutlineStack::default();
+            if let Some(base) = entity.get::<MeshMaterial3d<OutlineMaterial>>() {
+                stack.insert(OutlineLayer::Base, base.0.clone());
+            }
+            entity.insert(stack);
+        }
+        let mut stack = entity.get_mut::<OutlineStack>().unwrap();
+        match outline {
+            Some(outline) => {
+                stack.insert(layer, outline);
+            }
+            // Avoid flagging the stack as changed for nothing.
+            None if stack.get(layer).is_some() => {
+                stack.remove(layer);
+            }
+            None => {}
+        }
+    });
+}
+
+type BaseOutlineChanged = (With<OutlineStack>, Changed<MeshMaterial3d<OutlineMaterial>>);
+
+/// Adopts the outlines set or removed by the game on entities with an
+/// [`OutlineStack`] as their [`OutlineLayer::Base`], since picking creates the
+/// stack on the first interaction.
+pub(crate) fn adopt_base_outlines(
+    q_changed: Query<(Entity, &MeshMaterial3d<OutlineMaterial>), BaseOutlineChanged>,
+    mut q_stack: Query<(&mut OutlineStack, Has<MeshMaterial3d<OutlineMaterial>>)>,
+    mut removed: RemovedComponents<MeshMaterial3d<OutlineMaterial>>,
+) {
+    for (entity, material) in q_changed.iter() {
+        let Ok((mut stack, _)) = q_stack.get_mut(entity) else {
+            continue;
+        };
+        if stack.drawn.as_ref() != Some(&material.0) {
+            stack.insert(OutlineLayer::Base, material.0.clone());
+        }
+    }
+    for entity in removed.read() {
+        let Ok((mut stack, has_material)) = q_stack.get_mut(entity) else {
+            continue;
+        };
+        // The stack forgets what it drew when it removes the material itself.
+        if !has_material && stack.drawn.is_some() {
+            stack.drawn = None;
+            stack.remove(OutlineLayer::Base);
+        }
+    }
+}
+
+/// Draws the top outline of the changed [`OutlineStack`]s.
+pub(crate) fn apply_outline_stacks(
+    mut commands: Commands,
+    mut q_stack: Query<(Entity, &mut OutlineStack), Changed<OutlineStack>>,
+) {
+    for (entity, mut stack) in q_stack.iter_mut() {
+        // Don't flag the stack as changed again.
+        let stack = stack.bypass_change_detection();
+        stack.drawn = stack.top().map(|(_, outline)| outline.clone());
+        match stack.top() {
+            Some((_, outline)) => {
+                commands
+                    .entity(entity)
+                    .insert(MeshMaterial3d(outline.clone()));
+            }
+            None => {
+                commands
+                    .entity(entity)
+                    .remove::<MeshMaterial3d<OutlineMaterial>>();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{
+        asset::AssetPlugin,
+        ecs::system::SystemId,
+        pbr::RenderMaterialInstances,
+        render::{render_asset::RenderAssets, sync_world::MainEntity, MainWorld},
+    };
+
+    use super::*;
+    use crate::{
+        outline::{extract_outlines, prepare_outlines, ExtractedOutlines, RenderOutlineInstances},
+        Outline,
+    };
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<OutlineMaterial>()
+            .add_systems(
+                PostUpdate,
+                (adopt_base_outlines, apply_outline_stacks).chain(),
+            );
+        app
+    }
+
+    fn outline(app: &mut App) -> Handle<OutlineMaterial> {
+        app.world_mut()
+            .resource_mut::<Assets<OutlineMaterial>>()
+            .add(OutlineMaterial::default())
+    }
+
+    fn drawn(app: &App, entity: Entity) -> Option<Handle<OutlineMaterial>> {
+        app.world()
+            .get::<MeshMaterial3d<OutlineMaterial>>(entity)
+            .map(|material| material.0.clone())
+    }
+
+    fn set_layer(
+        app: &mut App,
+        entity: Entity,
+        layer: OutlineLayer,
+        outline: Option<Handle<OutlineMaterial>>,
+    ) {
+        let mut commands = app.world_mut().commands();
+        set_outline_layer(&mut commands, entity, layer, outline);
+        app.world_mut().flush();
+        app.update();
+    }
+
+    #[test]
+    fn restores_the_outline_of_the_game() {
+        let mut app = app();
+        let (quest, hover) = (outline(&mut app), outline(&mut app));
+        let entity = app.world_mut().spawn(MeshMaterial3d(quest.clone())).id();
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, Some(hover.clone()));
+        assert_eq!(drawn(&app, entity), Some(hover));
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert_eq!(drawn(&app, entity), Some(quest));
+    }
+
+    #[test]
+    fn draws_the_layer_with_the_highest_priority() {
+        let mut app = app();
+        let (selected, pressed, custom) = (outline(&mut app), outline(&mut app), outline(&mut app));
+        let entity = app.world_mut().spawn_empty().id();
+
+        set_layer(
+            &mut app,
+            entity,
+            OutlineLayer::Pressed,
+            Some(pressed.clone()),
+        );
+        set_layer(
+            &mut app,
+            entity,
+            OutlineLayer::Selected,
+            Some(selected.clone()),
+        );
+        assert_eq!(drawn(&app, entity), Some(pressed));
+
+        set_layer(
+            &mut app,
+            entity,
+            OutlineLayer::Custom(0),
+            Some(custom.clone()),
+        );
+        assert_eq!(drawn(&app, entity), Some(custom));
+
+        set_layer(&mut app, entity, OutlineLayer::Custom(0), None);
+        set_layer(&mut app, entity, OutlineLayer::Pressed, None);
+        assert_eq!(drawn(&app, entity), Some(selected));
+
+        set_layer(&mut app, entity, OutlineLayer::Selected, None);
+        assert_eq!(drawn(&app, entity), None);
+    }
+
+    #[test]
+    fn adopts_the_outline_set_after_the_first_interaction() {
+        let mut app = app();
+        let (quest, hover) = (outline(&mut app), outline(&mut app));
+        let entity = app.world_mut().spawn_empty().id();
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, Some(hover.clone()));
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert_eq!(drawn(&app, entity), None);
+
+        app.world_mut()
+            .entity_mut(entity)
+            .insert(MeshMaterial3d(quest.clone()));
+        app.update();
+        set_layer(&mut app, entity, OutlineLayer::Hovered, Some(hover.clone()));
+        assert_eq!(drawn(&app, entity), Some(hover.clone()));
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert_eq!(drawn(&app, entity), Some(quest));
+
+        app.world_mut()
+            .entity_mut(entity)
+            .remove::<MeshMaterial3d<OutlineMaterial>>();
+        app.update();
+        set_layer(&mut app, entity, OutlineLayer::Hovered, Some(hover.clone()));
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert_eq!(drawn(&app, entity), None);
+    }
+
+    #[test]
+    fn removing_a_layer_creates_no_stack() {
+        let mut app = app();
+        let entity = app.world_mut().spawn_empty().id();
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert!(!app.world().entity(entity).contains::<OutlineStack>());
+    }
+
+    /// A render world extracting the [`Outline`] components of the app.
+    fn render_world() -> (World, SystemId) {
+        let mut render_world = World::new();
+        render_world.init_resource::<MainWorld>();
+        render_world.init_resource::<ExtractedOutlines>();
+        render_world.init_resource::<RenderOutlineInstances>();
+        let extract = render_world.register_system(extract_outlines);
+        (render_world, extract)
+    }
+
+    /// The width of the [`Outline`] component drawn for `entity`, if any.
+    fn drawn_outline_width(
+        app: &mut App,
+        (render_world, extract): &mut (World, SystemId),
+        entity: Entity,
+    ) -> Option<f32> {
+        std::mem::swap(
+            app.world_mut(),
+            &mut render_world.resource_mut::<MainWorld>(),
+        );
+        render_world.run_system(*extract).unwrap();
+        std::mem::swap(
+            app.world_mut(),
+            &mut render_world.resource_mut::<MainWorld>(),
+        );
+        render_world.run_system_cached(prepare_outlines).unwrap();
+        render_world
+            .resource::<RenderOutlineInstances>()
+            .get(
+                &MainEntity::from(entity),
+                &RenderMaterialInstances::default(),
+                &RenderAssets::default(),
+            )
+            .map(|outline| outline.width)
+    }
+
+    #[test]
+    fn overrides_the_outline_component() {
+        let mut app = app();
+        let mut render_world = render_world();
+        let hover = outline(&mut app);
+        let entity = app
+            .world_mut()
+            .spawn(Outline {
+                width: 7.0,
+                ..default()
+            })
+            .id();
+        assert_eq!(
+            drawn_outline_width(&mut app, &mut render_world, entity),
+            Some(7.0)
+        );
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, Some(hover.clone()));
+        assert_eq!(drawn(&app, entity), Some(hover));
+        assert_eq!(
+            drawn_outline_width(&mut app, &mut render_world, entity),
+            None
+        );
+
+        set_layer(&mut app, entity, OutlineLayer::Hovered, None);
+        assert_eq!(drawn(&app, entity), None);
+        assert_eq!(
+            drawn_outline_width(&mut app, &mut render_world, entity),
+            Some(7.0)
+        );
+    }
+}
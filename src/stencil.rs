@@ -0,0 +1,467 @@
+//! Extruded outlines restricted to the outside of the silhouettes of meshes.
+//!
+//! The main depth buffer has no stencil aspect, so [`OutlineMethod::StencilExtrusion`]
+//! outlines are drawn in their own phase after the main pass, with a dedicated
+//! depth-stencil texture: the meshes first mark their silhouettes in the stencil
+//! buffer, then the extruded hulls are only drawn where no silhouette was marked.
+//! The depth of the scene is copied into that texture first, so both are depth
+//! tested like the other outlines.
+//!
+//! [`OutlineMethod::StencilExtrusion`]: crate::OutlineMethod::StencilExtrusion
+
+use std::ops::Range;
+
+use bevy::{
+    core_pipeline::{
+        core_3d::Camera3dDepthTextureUsage,
+        fullscreen_vertex_shader::fullscreen_shader_vertex_state,
+    },
+    ecs::query::QueryItem,
+    math::FloatOrd,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{NodeRunError, RenderGraphContext, RenderLabel, ViewNode},
+        render_phase::{
+            CachedRenderPipelinePhaseItem, DrawFunctionId, PhaseItem, PhaseItemExtraIndex,
+            SortedPhaseItem, ViewSortedRenderPhases,
+        },
+        render_resource::{
+            BindGroupEntry, BindGroupLayout, BindingResource, CachedRenderPipelineId,
+            ColorTargetState, ColorWrites, CompareFunction, DepthStencilState, Extent3d,
+            FragmentState, LoadOp, MultisampleState, Operations, PipelineCache, PrimitiveState,
+            RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            SpecializedRenderPipeline, SpecializedRenderPipelines, StoreOp, TextureDescriptor,
+            TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice},
+        sync_world::MainEntity,
+        texture::{CachedTexture, TextureCache},
+        view::{ViewDepthTexture, ViewTarget},
+    },
+};
+
+use crate::{
+    post_process::texture_layout_entry, DefaultOutlineMethod, Outline, OutlineMaterial,
+    OutlineMethod, OutlineRendered,
+};
+
+/// The format of the depth-stencil texture the stencil outlines are drawn with.
+pub(crate) const OUTLINE_STENCIL_FORMAT: TextureFormat = TextureFormat::Depth24PlusStencil8;
+
+/// The stencil value marking the silhouettes of outlined meshes.
+const OUTLINE_STENCIL_REFERENCE: u32 = 1;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub(crate) struct StencilOutlineLabel;
+
+/// A phase item of an outline drawn with [`OutlinePass::StencilMask`] or
+/// [`OutlinePass::StencilHull`].
+///
+/// [`OutlinePass::StencilMask`]: crate::OutlinePass::StencilMask
+/// [`OutlinePass::StencilHull`]: crate::OutlinePass::StencilHull
+pub struct OutlineStencil3d {
+    pub distance: f32,
+    /// Whether this item marks a silhouette. Silhouettes are drawn before any hull.
+    pub mask: bool,
+    pub pipeline: CachedRenderPipelineId,
+    pub entity: (Entity, MainEntity),
+    pub draw_function: DrawFunctionId,
+    pub batch_range: Range<u32>,
+    pub extra_index: PhaseItemExtraIndex,
+}
+
+impl PhaseItem for OutlineStencil3d {
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity.0
+    }
+
+    fn main_entity(&self) -> MainEntity {
+        self.entity.1
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+
+    #[inline]
+    fn batch_range(&self) -> &Range<u32> {
+        &self.batch_range
+    }
+
+    #[inline]
+    fn batch_range_mut(&mut self) -> &mut Range<u32> {
+        &mut self.batch_range
+    }
+
+    #[inline]
+    fn extra_index(&self) -> PhaseItemExtraIndex {
+        self.extra_index
+    }
+
+    #[inline]
+    fn batch_range_and_extra_index_mut(&mut self) -> (&mut Range<u32>, &mut PhaseItemExtraIndex) {
+        (&mut self.batch_range, &mut self.extra_index)
+    }
+}
+
+impl SortedPhaseItem for OutlineStencil3d {
+    type SortKey = (bool, FloatOrd);
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        (!self.mask, FloatOrd(self.distance))
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for OutlineStencil3d {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+/// Marks the cameras whose depth texture was made sampleable for the stencil outlines.
+#[derive(Component)]
+pub(crate) struct OutlineSampledDepth;
+
+type StencilOutlineQueryData = (
+    Option<&'static Outline>,
+    Option<&'static MeshMaterial3d<OutlineMaterial>>,
+);
+
+/// Allows the depth texture of 3D cameras to be sampled while stencil outlines
+/// exist, so it can be copied into the depth-stencil texture of these outlines.
+pub(crate) fn allow_sampling_camera_depth(
+    mut commands: Commands,
+    default_method: Res<DefaultOutlineMethod>,
+    materials: Res<Assets<OutlineMaterial>>,
+    q_outline: Query<StencilOutlineQueryData, With<OutlineRendered>>,
+    mut q_camera: Query<(Entity, &mut Camera3d, Has<OutlineSampledDepth>)>,
+) {
+    let is_stencil = |method: Option<OutlineMethod>| {
+        method.unwrap_or(default_method.0) == OutlineMethod::StencilExtrusion
+    };
+    let needed = q_outline.iter().any(|(outline, material)| {
+        outline.is_some_and(|outline| outline.enabled && is_stencil(outline.method))
+            || material
+                .and_then(|material| materials.get(material.id()))
+                .is_some_and(|material| is_stencil(material.method))
+    });
+    for (entity, mut camera, sampled) in q_camera.iter_mut() {
+        let usages = TextureUsages::from(camera.depth_texture_usages);
+        // Avoid flagging the camera as changed for nothing, and leave the usages
+        // set by the game alone.
+        if needed && !usages.contains(TextureUsages::TEXTURE_BINDING) {
+            camera.depth_texture_usages =
+                Camera3dDepthTextureUsage::from(usages | TextureUsages::TEXTURE_BINDING);
+            commands.entity(entity).insert(OutlineSampledDepth);
+        } else if !needed && sampled {
+            camera.depth_texture_usages =
+                Camera3dDepthTextureUsage::from(usages - TextureUsages::TEXTURE_BINDING);
+            commands.entity(entity).remove::<OutlineSampledDepth>();
+        }
+    }
+}
+
+#[derive(Component)]
+pub(crate) struct ViewOutlineStencilTexture {
+    texture: CachedTexture,
+    depth_pipeline: CachedRenderPipelineId,
+    multisampled: bool,
+}
+
+type StencilViewQueryData = (
+    Entity,
+    &'static ExtractedCamera,
+    &'static ViewTarget,
+    &'static Msaa,
+);
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_outline_stencil_textures(
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
+    depth_pipeline: Res<OutlineStencilDepthPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OutlineStencilDepthPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    stencil_phases: Res<ViewSortedRenderPhases<OutlineStencil3d>>,
+    views: Query<StencilViewQueryData>,
+) {
+    for (view_entity, camera, view_target, msaa) in views.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        if stencil_phases
+            .get(&view_entity)
+            .is_none_or(|phase| phase.items.is_empty())
+        {
+            continue;
+        }
+        let texture = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("outline stencil texture"),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: msaa.samples(),
+                dimension: TextureDimension::D2,
+                format: OUTLINE_STENCIL_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT,
+                view_formats: &[],
+            },
+        );
+        let key = OutlineStencilDepthPipelineKey {
+            format: view_target.main_texture_format(),
+            samples: msaa.samples(),
+        };
+        commands
+            .entity(view_entity)
+            .insert(ViewOutlineStencilTexture {
+                texture,
+                depth_pipeline: pipelines.specialize(&pipeline_cache, &depth_pipeline, key),
+                multisampled: key.samples > 1,
+            });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct OutlineStencilDepthPipelineKey {
+    /// The format of the main texture of the view.
+    format: TextureFormat,
+    samples: u32,
+}
+
+/// Copies the depth of the scene into the depth-stencil texture of the stencil
+/// outlines.
+#[derive(Resource)]
+pub(crate) struct OutlineStencilDepthPipeline {
+    /// Layouts for single sampled and multisampled depth textures.
+    layouts: [BindGroupLayout; 2],
+    shader_handle: Handle<Shader>,
+}
+
+impl FromWorld for OutlineStencilDepthPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(
+                Some("outline stencil depth layout"),
+                &[texture_layout_entry(
+                    0,
+                    TextureSampleType::Depth,
+                    multisampled,
+                )],
+            )
+        });
+        let shader_handle = world.resource::<AssetServer>().add(Shader::from_wgsl(
+            include_str!("render/stencil_depth.wgsl"),
+            "render/stencil_depth.wgsl",
+        ));
+        Self {
+            layouts,
+            shader_handle,
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for OutlineStencilDepthPipeline {
+    type Key = OutlineStencilDepthPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let multisampled = key.samples > 1;
+        let mut shader_defs = vec![];
+        if multisampled {
+            shader_defs.push("MULTISAMPLED".into());
+        }
+        RenderPipelineDescriptor {
+            label: Some("outline_stencil_depth_pipeline".into()),
+            layout: vec![self.layouts[multisampled as usize].clone()],
+            push_constant_ranges: vec![],
+            vertex: fullscreen_shader_vertex_state(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: Some(DepthStencilState {
+                format: OUTLINE_STENCIL_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Always,
+                stencil: default(),
+                bias: default(),
+            }),
+            multisample: MultisampleState {
+                count: key.samples,
+                ..default()
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader_handle.clone(),
+                shader_defs,
+                entry_point: "fragment".into(),
+                // The pass draws onto the view target, but only the depth is written.
+                targets: vec![Some(ColorTargetState {
+                    format: key.format,
+                    blend: None,
+                    write_mask: ColorWrites::empty(),
+                })],
+            }),
+            zero_initialize_workgroup_memory: true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StencilOutlineNode;
+
+impl ViewNode for StencilOutlineNode {
+    type ViewQuery = (
+        &'static ExtractedCamera,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static ViewOutlineStencilTexture,
+    );
+
+    fn run<'w>(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (camera, view_target, depth, stencil_texture): QueryItem<'w, Self::ViewQuery>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.view_entity();
+        let Some(stencil_phase) = world
+            .resource::<ViewSortedRenderPhases<OutlineStencil3d>>()
+            .get(&view_entity)
+        else {
+            return Ok(());
+        };
+        if stencil_phase.items.is_empty() {
+            return Ok(());
+        }
+        let Some(depth_pipeline) = world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(stencil_texture.depth_pipeline)
+        else {
+            return Ok(());
+        };
+
+        let depth_bind_group = render_context.render_device().create_bind_group(
+            Some("outline stencil depth bind group"),
+            &world.resource::<OutlineStencilDepthPipeline>().layouts
+                [stencil_texture.multisampled as usize],
+            &[BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(depth.view()),
+            }],
+        );
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("outline_stencil_pass"),
+            color_attachments: &[Some(view_target.get_color_attachment())],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &stencil_texture.texture.default_view,
+                // Overwritten with the depth of the scene below.
+                depth_ops: Some(Operations {
+                    load: LoadOp::Clear(0.0),
+                    store: StoreOp::Discard,
+                }),
+                stencil_ops: Some(Operations {
+                    load: LoadOp::Clear(0),
+                    store: StoreOp::Discard,
+                }),
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        pass.set_render_pipeline(depth_pipeline);
+        pass.set_bind_group(0, &depth_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+        pass.set_stencil_reference(OUTLINE_STENCIL_REFERENCE);
+        if let Err(err) = stencil_phase.render(&mut pass, world, view_entity) {
+            error!("Error encountered while rendering the outline stencil phase {err:?}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(default_method: OutlineMethod) -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+            .init_asset::<OutlineMaterial>()
+            .insert_resource(DefaultOutlineMethod(default_method))
+            .add_systems(PostUpdate, allow_sampling_camera_depth);
+        app
+    }
+
+    fn sampled(app: &App, camera: Entity) -> bool {
+        let camera = app.world().get::<Camera3d>(camera).unwrap();
+        TextureUsages::from(camera.depth_texture_usages).contains(TextureUsages::TEXTURE_BINDING)
+    }
+
+    #[test]
+    fn depth_sampled_only_while_stencil_outlines_exist() {
+        let mut app = app(OutlineMethod::Extrusion);
+        let camera = app.world_mut().spawn(Camera3d::default()).id();
+        let extruded = app.world_mut().spawn(Outline::default()).id();
+        app.update();
+        assert!(!sampled(&app, camera));
+
+        let stencil = app
+            .world_mut()
+            .spawn(Outline {
+                method: Some(OutlineMethod::StencilExtrusion),
+                ..default()
+            })
+            .id();
+        app.update();
+        assert!(sampled(&app, camera));
+
+        app.world_mut().despawn(stencil);
+        app.update();
+        assert!(!sampled(&app, camera));
+
+        app.world_mut().despawn(extruded);
+        app.update();
+        assert!(!sampled(&app, camera));
+    }
+
+    #[test]
+    fn usages_set_by_the_game_are_kept() {
+        let mut app = app(OutlineMethod::Extrusion);
+        let usages = TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
+        let camera = app
+            .world_mut()
+            .spawn(Camera3d {
+                depth_texture_usages: usages.into(),
+                ..default()
+            })
+            .id();
+        app.update();
+        assert!(sampled(&app, camera));
+    }
+
+    #[test]
+    fn depth_sampled_for_materials_with_the_default_method() {
+        let mut app = app(OutlineMethod::StencilExtrusion);
+        let camera = app.world_mut().spawn(Camera3d::default()).id();
+        let material = app
+            .world_mut()
+            .resource_mut::<Assets<OutlineMaterial>>()
+            .add(OutlineMaterial::default());
+        app.world_mut()
+            .spawn((OutlineRendered, MeshMaterial3d(material)));
+        app.update();
+        assert!(sampled(&app, camera));
+    }
+}
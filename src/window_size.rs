@@ -1,71 +1,91 @@
 use bevy::{
     ecs::{
-        change_detection::DetectChanges,
-        query::{ROQueryItem, With},
-        system::{lifetimeless::SRes, Query, SystemParamItem},
-        world::Ref,
+        query::ROQueryItem,
+        system::{
+            lifetimeless::{Read, SRes},
+            Query, SystemParamItem,
+        },
     },
-    math::Vec2,
-    prelude::{Commands, Res, ResMut, Resource},
+    math::{Vec2, Vec4Swizzles},
+    prelude::{Camera, Commands, Component, Entity, Res, ResMut, Resource},
     render::{
         render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
-        render_resource::{BindGroup, BindGroupEntry, Buffer, ShaderType},
+        render_resource::{BindGroup, BindGroupEntry, BufferId, DynamicUniformBuffer, ShaderType},
         renderer::{RenderDevice, RenderQueue},
+        sync_world::RenderEntity,
+        view::ExtractedView,
         Extract,
     },
-    window::{PrimaryWindow, Window},
 };
-use bytemuck::cast_slice;
 
 use crate::OutlinePipeline;
 
-#[derive(Resource)]
-pub(crate) struct ExtractedWindowSize {
-    width: f32,
-    height: f32,
-}
-
-#[derive(ShaderType)]
+#[derive(Clone, ShaderType)]
 pub(crate) struct DoubleReciprocalWindowSizeUniform {
     size: Vec2,
+    scale_factor: f32,
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub(crate) struct DoubleReciprocalWindowSizeMeta {
-    pub buffer: Buffer,
-    pub bind_group: Option<BindGroup>,
+    pub uniforms: DynamicUniformBuffer<DoubleReciprocalWindowSizeUniform>,
+    /// Recreated only when [`Self::uniforms`] is reallocated.
+    pub bind_group: Option<(BufferId, BindGroup)>,
+}
+
+/// Offset of the window size uniform of a view inside
+/// [`DoubleReciprocalWindowSizeMeta::uniforms`].
+#[derive(Component)]
+pub(crate) struct ViewWindowSizeUniformOffset {
+    pub offset: u32,
 }
 
-pub(crate) fn extract_window_size(
+/// Scale factor of the render target of a camera, used to convert outline
+/// widths in logical pixels into physical pixels.
+#[derive(Component, Clone, Copy)]
+pub(crate) struct ExtractedViewScaleFactor(pub f32);
+
+pub(crate) fn extract_view_scale_factor(
     mut commands: Commands,
-    q_window: Extract<Query<Ref<Window>, With<PrimaryWindow>>>,
+    q_camera: Extract<Query<(&RenderEntity, &Camera)>>,
 ) {
-    let Ok(window) = q_window.get_single() else {
-        return;
-    };
-    if !window.is_changed() {
-        return;
+    for (render_entity, camera) in q_camera.iter() {
+        if !camera.is_active {
+            continue;
+        }
+        let scale_factor = camera.target_scaling_factor().unwrap_or(1.0);
+        commands
+            .entity(render_entity.id())
+            .insert(ExtractedViewScaleFactor(scale_factor));
     }
-    let width = window.width();
-    let height = window.height();
-    commands.insert_resource(ExtractedWindowSize { width, height });
 }
 
+/// Compute the double reciprocal size of every view from its own viewport, so
+/// that secondary windows, split-screen viewports and cameras rendering to an
+/// image all get outlines of the right pixel width.
 pub(crate) fn prepare_window_size(
-    window_size: Res<ExtractedWindowSize>,
-    window_size_meta: ResMut<DoubleReciprocalWindowSizeMeta>,
+    mut commands: Commands,
+    mut window_size_meta: ResMut<DoubleReciprocalWindowSizeMeta>,
+    render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    views: Query<(Entity, &ExtractedView, Option<&ExtractedViewScaleFactor>)>,
 ) {
-    if window_size.is_changed() {
-        let window_size_uniform = DoubleReciprocalWindowSizeUniform {
-            size: Vec2::new(2.0 / window_size.width, 2.0 / window_size.height),
-        };
-        render_queue.write_buffer(
-            &window_size_meta.buffer,
-            0,
-            cast_slice(&[window_size_uniform.size]),
-        )
+    window_size_meta.uniforms.clear();
+    for (entity, view, scale_factor) in views.iter() {
+        let viewport_size = view.viewport.zw().as_vec2().max(Vec2::ONE);
+        let offset = window_size_meta
+            .uniforms
+            .push(&DoubleReciprocalWindowSizeUniform {
+                size: 2.0 / viewport_size,
+                scale_factor: scale_factor.map_or(1.0, |scale_factor| scale_factor.0),
+            });
+        commands
+            .entity(entity)
+            .insert(ViewWindowSizeUniformOffset { offset });
     }
+    window_size_meta
+        .uniforms
+        .write_buffer(&render_device, &render_queue);
 }
 
 pub(crate) fn queue_window_size_bind_group(
@@ -73,34 +93,41 @@ pub(crate) fn queue_window_size_bind_group(
     mut double_reciprocal_window_size_meta: ResMut<DoubleReciprocalWindowSizeMeta>,
     pipeline: Res<OutlinePipeline>,
 ) {
+    let meta = &mut *double_reciprocal_window_size_meta;
+    let (Some(buffer), Some(binding)) = (meta.uniforms.buffer(), meta.uniforms.binding()) else {
+        return;
+    };
+    if matches!(&meta.bind_group, Some((buffer_id, _)) if *buffer_id == buffer.id()) {
+        return;
+    }
     let bind_group = render_device.create_bind_group(
         Some("window size bind group"),
         &pipeline.window_size_layout,
         &[BindGroupEntry {
             binding: 0,
-            resource: double_reciprocal_window_size_meta
-                .buffer
-                .as_entire_binding(),
+            resource: binding,
         }],
     );
-    double_reciprocal_window_size_meta.bind_group = Some(bind_group);
+    meta.bind_group = Some((buffer.id(), bind_group));
 }
 
 pub(crate) struct SetWindowSizeBindGroup<const I: usize>;
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetWindowSizeBindGroup<I> {
     type Param = SRes<DoubleReciprocalWindowSizeMeta>;
     type ItemQuery = ();
-    type ViewQuery = ();
+    type ViewQuery = Read<ViewWindowSizeUniformOffset>;
 
     fn render<'w>(
         _item: &P,
-        _view: ROQueryItem<'w, Self::ViewQuery>,
+        view_window_size: ROQueryItem<'w, Self::ViewQuery>,
         _entity: Option<ROQueryItem<'w, Self::ItemQuery>>,
         param: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let window_size_bind_group = param.into_inner().bind_group.as_ref().unwrap();
-        pass.set_bind_group(I, window_size_bind_group, &[]);
+        let Some((_, window_size_bind_group)) = param.into_inner().bind_group.as_ref() else {
+            return RenderCommandResult::Skip;
+        };
+        pass.set_bind_group(I, window_size_bind_group, &[view_window_size.offset]);
         RenderCommandResult::Success
     }
 }